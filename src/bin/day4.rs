@@ -1,12 +1,29 @@
 use std::cmp::{max, min};
 use std::fs::File;
 use std::io::{BufRead, BufReader, stdin};
+use std::time::Instant;
 use std::{env, mem};
 
+/// How cells outside the grid are counted as neighbours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edge {
+    /// Outside is empty floor.
+    Empty,
+    /// Outside is a wall, counted as occupied.
+    Wall,
+    /// The grid wraps around: row 0 neighbours the last row, column 0 the last column.
+    /// Each of the eight neighbouring offsets counts separately, so on a grid less than three
+    /// cells wide or high the same cell can be a neighbour twice, or a roll its own neighbour.
+    Wrap,
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: {} <input_file>", args[0]);
+        eprintln!(
+            "Usage: {} <input_file> [--bits] [--edge empty|wall|wrap] [--bench <scale>]",
+            args[0]
+        );
         std::process::exit(1);
     }
 
@@ -24,12 +41,59 @@ fn main() {
     let lines = file.lines();
     let text = lines.map(Result::unwrap).collect::<Vec<_>>();
 
-    let result = paper(text);
+    let edge = match args.iter().position(|a| a == "--edge") {
+        None => Edge::Empty,
+        Some(pos) => match args.get(pos + 1).map(String::as_str) {
+            Some("empty") => Edge::Empty,
+            Some("wall") => Edge::Wall,
+            Some("wrap") => Edge::Wrap,
+            other => {
+                eprintln!("Unknown edge mode {other:?}, expected empty, wall or wrap");
+                std::process::exit(1);
+            }
+        },
+    };
+
+    if let Some(pos) = args.iter().position(|a| a == "--bench") {
+        let scale = args
+            .get(pos + 1)
+            .map(|s| s.parse::<usize>().expect("Invalid scale"))
+            .unwrap_or(10);
+        bench(&text, scale, edge);
+        return;
+    }
+
+    let result = if args.iter().any(|a| a == "--bits") {
+        paper_bits(text, edge)
+    } else {
+        paper(text, edge)
+    };
 
     println!("{result}");
 }
 
-fn paper(mut lines: Vec<String>) -> u64 {
+/// Tiles the grid `scale` times in each direction and times both implementations on it.
+fn bench(lines: &[String], scale: usize, edge: Edge) {
+    let big = enlarge(lines, scale);
+    let width = big.first().map(String::len).unwrap_or(0);
+    println!("grid: {width}x{}", big.len());
+
+    let start = Instant::now();
+    let by_bits = paper_bits(big.clone(), edge);
+    println!("bits:   {by_bits}\t{:?}", start.elapsed());
+
+    let start = Instant::now();
+    let by_string = paper(big, edge);
+    println!("string: {by_string}\t{:?}", start.elapsed());
+}
+
+fn enlarge(lines: &[String], scale: usize) -> Vec<String> {
+    (0..scale)
+        .flat_map(|_| lines.iter().map(|line| line.repeat(scale)))
+        .collect()
+}
+
+fn paper(mut lines: Vec<String>, edge: Edge) -> u64 {
     let mut removed = 0;
 
     let mut current_lines = &mut lines;
@@ -46,13 +110,18 @@ fn paper(mut lines: Vec<String>) -> u64 {
             for (j, c) in line.chars().enumerate() {
                 let mut neighbours = 0;
                 if c == '@' {
-                    if i > 0 {
-                        neighbours += check3(&current_lines[i - 1], j)
-                    }
-                    neighbours += check3(&current_lines[i], j);
-                    if i + 1 < current_lines.len() {
-                        neighbours += check3(&current_lines[i + 1], j)
-                    }
+                    let height = current_lines.len();
+                    neighbours += if i > 0 {
+                        check3(&current_lines[i - 1], j, edge)
+                    } else {
+                        outside3(&current_lines[height - 1], j, edge)
+                    };
+                    neighbours += check3(&current_lines[i], j, edge);
+                    neighbours += if i + 1 < height {
+                        check3(&current_lines[i + 1], j, edge)
+                    } else {
+                        outside3(&current_lines[0], j, edge)
+                    };
                     if neighbours <= 4 {
                         removed += 1;
                         next_line.replace_range(j..=j, ".");
@@ -71,20 +140,200 @@ fn paper(mut lines: Vec<String>) -> u64 {
     removed
 }
 
+/// Packed bit-plane representation of the floor: one bit per cell, `words` 64-bit words per row,
+/// column `j` at bit `j % 64` of word `j / 64`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct BitGrid {
+    width: usize,
+    height: usize,
+    words: usize,
+    edge: Edge,
+    cells: Vec<u64>,
+}
+
+impl BitGrid {
+    fn from_lines(lines: &[String], edge: Edge) -> BitGrid {
+        let width = lines.iter().map(String::len).max().unwrap_or(0);
+        let height = lines.len();
+        let words = width.div_ceil(64);
+        let mut cells = vec![0u64; words * height];
+        for (i, line) in lines.iter().enumerate() {
+            for (j, b) in line.bytes().enumerate() {
+                if b == b'@' {
+                    cells[i * words + j / 64] |= 1 << (j % 64);
+                }
+            }
+        }
+        BitGrid {
+            width,
+            height,
+            words,
+            edge,
+            cells,
+        }
+    }
+
+    #[inline]
+    fn row(&self, i: usize) -> &[u64] {
+        &self.cells[i * self.words..(i + 1) * self.words]
+    }
+
+    /// Mask of the valid columns in word `w` of a row.
+    #[inline]
+    fn mask(&self, w: usize) -> u64 {
+        let bits = self.width - w * 64;
+        if bits >= 64 { !0 } else { (1 << bits) - 1 }
+    }
+
+    #[inline]
+    fn get(&self, i: usize, j: usize) -> u64 {
+        (self.row(i)[j / 64] >> (j % 64)) & 1
+    }
+
+    /// Horizontal 3-cell sums of a row as a bit-sliced 2-bit number `(ones, twos)` per column.
+    fn row_sums(&self, i: usize, ones: &mut [u64], twos: &mut [u64]) {
+        let row = self.row(i);
+        let (left_edge, right_edge) = match self.edge {
+            Edge::Empty => (0, 0),
+            Edge::Wall => (1, 1),
+            Edge::Wrap => (self.get(i, self.width - 1), self.get(i, 0)),
+        };
+        for w in 0..self.words {
+            let c = row[w];
+            let carry_in = if w > 0 { row[w - 1] >> 63 } else { left_edge };
+            let carry_out = if w + 1 < self.words {
+                row[w + 1] << 63
+            } else {
+                right_edge << ((self.width - 1) % 64)
+            };
+            let l = (c << 1) | carry_in;
+            let r = (c >> 1) | carry_out;
+            ones[w] = l ^ c ^ r;
+            twos[w] = (l & c) | (l & r) | (c & r);
+        }
+    }
+
+    /// Row sums for the row beyond the top or bottom edge; `wrapped` is the row it wraps around to.
+    fn outside_sums(&self, wrapped: usize, ones: &mut [u64], twos: &mut [u64]) {
+        match self.edge {
+            Edge::Empty => {
+                ones.fill(0);
+                twos.fill(0);
+            }
+            Edge::Wall => {
+                ones.fill(!0);
+                twos.fill(!0);
+            }
+            Edge::Wrap => self.row_sums(wrapped, ones, twos),
+        }
+    }
+
+    /// Removes every roll with fewer than four occupied neighbours, returning how many went.
+    fn step(&mut self, next: &mut Vec<u64>) -> u64 {
+        let words = self.words;
+        next.clear();
+        next.resize(self.cells.len(), 0);
+
+        // rolling bit-sliced row sums for rows i-1, i, i+1
+        let mut above = (vec![0u64; words], vec![0u64; words]);
+        let mut here = (vec![0u64; words], vec![0u64; words]);
+        let mut below = (vec![0u64; words], vec![0u64; words]);
+        if self.height > 0 {
+            self.outside_sums(self.height - 1, &mut above.0, &mut above.1);
+            self.row_sums(0, &mut here.0, &mut here.1);
+        }
+
+        let mut removed = 0;
+        for i in 0..self.height {
+            if i + 1 < self.height {
+                self.row_sums(i + 1, &mut below.0, &mut below.1);
+            } else {
+                self.outside_sums(0, &mut below.0, &mut below.1);
+            }
+
+            let row = self.row(i);
+            for w in 0..words {
+                // above + here, 3 bits
+                let x0 = above.0[w] ^ here.0[w];
+                let c0 = above.0[w] & here.0[w];
+                let x1 = above.1[w] ^ here.1[w] ^ c0;
+                let x2 = (above.1[w] & here.1[w]) | (c0 & (above.1[w] ^ here.1[w]));
+                // + below, 4 bits
+                let y0 = x0 ^ below.0[w];
+                let k0 = x0 & below.0[w];
+                let y1 = x1 ^ below.1[w] ^ k0;
+                let k1 = (x1 & below.1[w]) | (k0 & (x1 ^ below.1[w]));
+                let y2 = x2 ^ k1;
+                let y3 = x2 & k1;
+
+                // the 3x3 sum includes the roll itself, so <= 4 means fewer than 4 neighbours
+                let crowded = y3 | (y2 & (y1 | y0));
+                let gone = row[w] & !crowded & self.mask(w);
+                removed += gone.count_ones() as u64;
+                next[i * words + w] = row[w] & !gone;
+            }
+
+            mem::swap(&mut above, &mut here);
+            mem::swap(&mut here, &mut below);
+        }
+
+        mem::swap(&mut self.cells, next);
+        removed
+    }
+}
+
+fn paper_bits(lines: Vec<String>, edge: Edge) -> u64 {
+    let mut grid = BitGrid::from_lines(&lines, edge);
+    // no columns, so no rolls, and no last column for a wrapped edge to read
+    if grid.width == 0 {
+        return 0;
+    }
+    let mut next = Vec::with_capacity(grid.cells.len());
+
+    let mut removed = 0;
+    loop {
+        let step = grid.step(&mut next);
+        if step == 0 {
+            break;
+        }
+        removed += step;
+    }
+    removed
+}
+
 #[inline]
-fn check3(line: &str, pos: usize) -> u64 {
+fn check3(line: &str, pos: usize, edge: Edge) -> u64 {
     let left = max(1, pos) - 1;
     let right = min(line.len() - 1, pos + 1);
-    line[left..=right].chars().filter(|&c| c == '@').count() as u64
+    let inside = line[left..=right].chars().filter(|&c| c == '@').count() as u64;
+    let at_left = pos == 0;
+    let at_right = pos + 1 >= line.len();
+    inside
+        + match edge {
+            Edge::Empty => 0,
+            Edge::Wall => at_left as u64 + at_right as u64,
+            Edge::Wrap => {
+                (at_left && line.ends_with('@')) as u64 + (at_right && line.starts_with('@')) as u64
+            }
+        }
+}
+
+/// Counts the three cells above the top row or below the bottom row; `wrapped` is the row on
+/// the opposite side of the grid.
+#[inline]
+fn outside3(wrapped: &str, pos: usize, edge: Edge) -> u64 {
+    match edge {
+        Edge::Empty => 0,
+        Edge::Wall => 3,
+        Edge::Wrap => check3(wrapped, pos, edge),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::*;
 
-    #[test]
-    fn example() {
-        let lines = r"..@@.@@@@.
+    const EXAMPLE_INPUT: &str = r"..@@.@@@@.
 @@@.@.@.@@
 @@@@@.@.@@
 @.@@@@..@.
@@ -93,18 +342,106 @@ mod tests {
 .@.@.@.@@@
 @.@@@.@@@@
 .@@@@@@@@.
-@.@.@@@.@."
-            .split('\n')
-            .map(String::from)
-            .collect();
-        let result = paper(lines);
+@.@.@@@.@.";
+
+    #[test]
+    fn example() {
+        let result = paper(example_lines(), Edge::Empty);
         assert_eq!(43, result);
     }
 
+    #[test]
+    fn example_bits() {
+        let lines = example_lines();
+        assert_eq!(43, paper_bits(lines.clone(), Edge::Empty));
+
+        // crosses several word boundaries
+        let big = enlarge(&lines, 20);
+        for edge in [Edge::Empty, Edge::Wall, Edge::Wrap] {
+            assert_eq!(paper(big.clone(), edge), paper_bits(big.clone(), edge));
+        }
+    }
+
+    #[test]
+    fn input_bits() {
+        let file = BufReader::new(File::open("src/bin/day4.txt").unwrap());
+        let lines = file.lines().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(
+            paper(lines.clone(), Edge::Empty),
+            paper_bits(lines, Edge::Empty)
+        );
+    }
+
+    #[test]
+    fn edges() {
+        let lines = example_lines();
+        assert_eq!(2, paper(lines.clone(), Edge::Wall));
+        assert_eq!(2, paper_bits(lines.clone(), Edge::Wall));
+        assert_eq!(3, paper(lines.clone(), Edge::Wrap));
+        assert_eq!(3, paper_bits(lines, Edge::Wrap));
+
+        // wrapping a tiled grid makes every tile identical, so the count scales exactly
+        let big = enlarge(&example_lines(), 3);
+        assert_eq!(9 * 3, paper_bits(big, Edge::Wrap));
+    }
+
+    #[test]
+    fn narrow_wrap() {
+        // nothing to wrap around to
+        for lines in [vec![], vec![String::new(); 2]] {
+            for edge in [Edge::Empty, Edge::Wall, Edge::Wrap] {
+                assert_eq!(0, paper(lines.clone(), edge));
+                assert_eq!(0, paper_bits(lines.clone(), edge));
+            }
+        }
+
+        // a lone roll is all eight of its own neighbours
+        let lines = vec!["@".to_string()];
+        assert_eq!(1, paper(lines.clone(), Edge::Empty));
+        assert_eq!(0, paper(lines.clone(), Edge::Wrap));
+        assert_eq!(0, paper_bits(lines, Edge::Wrap));
+
+        // two across: the other roll is left, right and all four diagonals
+        let lines = vec!["@@".to_string()];
+        assert_eq!(0, paper(lines.clone(), Edge::Wrap));
+        assert_eq!(0, paper_bits(lines, Edge::Wrap));
+        // the roll itself, then the other as both its left and its right
+        assert_eq!(3, check3("@@", 0, Edge::Wrap));
+
+        // two wide and three high, so only columns double up
+        let lines = ["@@", "..", "@."].map(String::from).to_vec();
+        assert_eq!(3, paper(lines.clone(), Edge::Wrap));
+        assert_eq!(3, paper_bits(lines, Edge::Wrap));
+        let lines = ["@@", "@."].map(String::from).to_vec();
+        assert_eq!(
+            paper(lines.clone(), Edge::Wrap),
+            paper_bits(lines, Edge::Wrap)
+        );
+    }
+
     #[test]
     fn simple() {
-        assert_eq!(2, check3("@@@", 0));
-        assert_eq!(3, check3("@@@", 1));
-        assert_eq!(2, check3("@@@", 2));
+        assert_eq!(2, check3("@@@", 0, Edge::Empty));
+        assert_eq!(3, check3("@@@", 1, Edge::Empty));
+        assert_eq!(2, check3("@@@", 2, Edge::Empty));
+
+        assert_eq!(3, check3("@@.", 0, Edge::Wall));
+        assert_eq!(2, check3("@@.", 2, Edge::Wall));
+        assert_eq!(2, check3(".@.", 0, Edge::Wall));
+
+        assert_eq!(2, check3("@@.", 0, Edge::Wrap));
+        assert_eq!(2, check3("@@.", 2, Edge::Wrap));
+        assert_eq!(2, check3("@.@", 0, Edge::Wrap));
+    }
+
+    #[test]
+    fn outside() {
+        assert_eq!(0, outside3("@@@", 1, Edge::Empty));
+        assert_eq!(3, outside3("...", 1, Edge::Wall));
+        assert_eq!(2, outside3("@.@", 0, Edge::Wrap));
+    }
+
+    fn example_lines() -> Vec<String> {
+        EXAMPLE_INPUT.split('\n').map(String::from).collect()
     }
 }
@@ -1,61 +1,198 @@
-use std::env;
+use aoc2025::interval_set::IntervalSet;
 use std::fs::File;
-use std::io::{BufRead, BufReader, stdin};
-use std::ops::RangeInclusive;
+use std::io::{BufRead, BufReader, Write, stdin, stdout};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::{env, fs, io, process, thread};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let filename = if args.len() < 2 {
-        "src/bin/day5.txt"
-    } else {
-        &args[1]
-    };
+    let socket = args
+        .iter()
+        .position(|a| a == "--socket")
+        .map(|pos| args.get(pos + 1).expect("Missing socket path").as_str());
+    let filename = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(i, a)| !a.starts_with("--") && args[i - 1] != "--socket")
+        .map(|(_, a)| a.as_str())
+        .unwrap_or("src/bin/day5.txt");
+    // merge touching ranges such as 5-10 and 11-20 as well as overlapping ones
+    let coalesce = args.iter().any(|a| a == "--coalesce");
+    // print the merged ranges
+    let list_ranges = args.iter().any(|a| a == "--ranges");
+    // answer queries on stdin instead of counting the file's IDs
+    let serve_stdin = args.iter().any(|a| a == "--serve");
 
-    let file: Box<dyn BufRead> = if filename == "-" {
+    let mut file: Box<dyn BufRead> = if filename == "-" {
         Box::new(stdin().lock())
     } else {
         Box::new(BufReader::new(
             File::open(filename).expect("Failed to open file"),
         ))
     };
-    let text = file.lines().map(Result::unwrap).collect::<Vec<_>>();
 
-    let result = fresh(text);
+    if serve_stdin || socket.is_some() {
+        let ranges = merge_ranges(
+            (&mut file)
+                .lines()
+                .map(Result::unwrap)
+                .take_while(|x| !x.is_empty()),
+            coalesce,
+        );
+        eprintln!("loaded {} ranges", ranges.range_count());
 
-    println!("{result}");
+        match socket {
+            Some(path) => listen(Arc::new(ranges), path).expect("Socket failed"),
+            // when the ranges came from stdin, the queries follow them after the blank line
+            None if filename == "-" => serve(&ranges, file, stdout().lock()).unwrap(),
+            None => serve(&ranges, stdin().lock(), stdout().lock()).unwrap(),
+        }
+        return;
+    }
+
+    let result = fresh(file.lines().map(Result::unwrap), coalesce);
+
+    if list_ranges {
+        for range in result.ranges.iter() {
+            println!("{}-{}", range.start(), range.end());
+        }
+    }
+    println!("ranges: {}", result.ranges.range_count());
+    println!("fresh ids: {}", result.fresh_ids);
+    println!("fresh range: {}", result.fresh_range);
 }
 
-fn fresh(lines: Vec<String>) -> usize {
-    let mut raw_ranges: Vec<RangeInclusive<usize>> = lines
-        .iter()
-        .take_while(|x| !x.is_empty())
-        .map(|r| {
-            let (from, to) = r.split_once('-').unwrap();
-            println!("from={from}, to={to}");
-            from.parse::<usize>().unwrap()..=to.parse::<usize>().unwrap()
-        })
-        .collect();
-    raw_ranges.sort_by_key(|r| *r.start());
-
-    let mut ranges: Vec<RangeInclusive<usize>> = Vec::with_capacity(raw_ranges.len());
-    ranges.push(raw_ranges.first().unwrap().clone());
-
-    for raw_range in raw_ranges.iter().skip(1) {
-        let last = ranges.last().unwrap().clone();
-        if raw_range.start() > last.end() {
-            ranges.push(raw_range.clone());
-        } else {
-            ranges.pop();
-            ranges.push(*last.start()..=*last.end().max(raw_range.end()));
+#[derive(Debug, PartialEq, Eq)]
+struct Freshness {
+    /// how many of the queried ingredient IDs fall in a fresh range
+    fresh_ids: usize,
+    /// how many IDs the fresh ranges cover in total
+    fresh_range: u128,
+    ranges: IntervalSet<usize>,
+}
+
+/// Streams the input: ranges are merged as they are read and each ID is checked as it arrives,
+/// so memory is bounded by the number of disjoint merged ranges rather than the input size.
+fn fresh(lines: impl IntoIterator<Item = String>, coalesce: bool) -> Freshness {
+    let mut lines = lines.into_iter();
+    let ranges = merge_ranges(lines.by_ref().take_while(|x| !x.is_empty()), coalesce);
+
+    let fresh_ids = lines
+        .filter(|x| !x.is_empty())
+        .map(|id| id.parse::<usize>().unwrap())
+        .filter(|id| ranges.contains(*id))
+        .count();
+
+    Freshness {
+        fresh_ids,
+        fresh_range: ranges.len(),
+        ranges,
+    }
+}
+
+fn merge_ranges(lines: impl IntoIterator<Item = String>, coalesce: bool) -> IntervalSet<usize> {
+    let mut ranges = if coalesce {
+        IntervalSet::coalescing()
+    } else {
+        IntervalSet::new()
+    };
+    ranges.extend(lines.into_iter().map(|r| {
+        let (from, to) = r.split_once('-').unwrap();
+        from.parse::<usize>().unwrap()..=to.parse::<usize>().unwrap()
+    }));
+    ranges
+}
+
+/// Answers newline-delimited queries against the ranges, one answer line per query:
+/// `<id>` gets `fresh` or `spoiled`, and `<from>-<to>` gets the number of fresh IDs in that range.
+fn serve(
+    ranges: &IntervalSet<usize>,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let query = line.trim();
+        if query.is_empty() {
+            continue;
+        }
+        writeln!(output, "{}", answer(ranges, query))?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn answer(ranges: &IntervalSet<usize>, query: &str) -> String {
+    match query.split_once('-') {
+        Some((from, to)) => match (from.trim().parse::<usize>(), to.trim().parse::<usize>()) {
+            (Ok(from), Ok(to)) if from <= to => ranges.count_in(from..=to).to_string(),
+            _ => format!("error: invalid range {query:?}"),
+        },
+        None => match query.parse::<usize>() {
+            Ok(id) if ranges.contains(id) => "fresh".to_string(),
+            Ok(_) => "spoiled".to_string(),
+            Err(_) => format!("error: invalid id {query:?}"),
+        },
+    }
+}
+
+/// Serves each connection to the Unix domain socket at `path` on its own thread, removing the
+/// socket file again on Ctrl-C or termination.
+fn listen(ranges: Arc<IntervalSet<usize>>, path: &str) -> io::Result<()> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    let _socket = SocketFile(path);
+    let cleanup = path.to_string();
+    ctrlc::set_handler(move || {
+        let _ = fs::remove_file(&cleanup);
+        process::exit(0);
+    })
+    .map_err(io::Error::other)?;
+    eprintln!("listening on {path}");
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let ranges = Arc::clone(&ranges);
+        thread::spawn(move || {
+            if let Err(e) = serve_stream(&ranges, stream) {
+                eprintln!("connection failed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Removes a socket left behind by a run that didn't shut down cleanly. Anything that isn't a
+/// socket, or a socket something is still listening on, is left for `bind` to fail on.
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() && UnixStream::connect(path).is_err() => {
+            fs::remove_file(path)
         }
+        _ => Ok(()),
+    }
+}
+
+/// A bound socket's file, removed when the listener stops.
+struct SocketFile<'a>(&'a str);
+
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.0);
     }
+}
 
-    ranges.iter().map(|r| r.end() - r.start() + 1).sum()
+fn serve_stream(ranges: &IntervalSet<usize>, stream: UnixStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    serve(ranges, reader, stream)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use std::net::Shutdown;
 
     #[test]
     fn example() {
@@ -71,9 +208,129 @@ mod tests {
 17
 32"
         .split('\n')
-        .map(String::from)
-        .collect();
-        let result = fresh(lines);
-        assert_eq!(14, result);
+        .map(String::from);
+        let result = fresh(lines, false);
+        assert_eq!(3, result.fresh_ids);
+        assert_eq!(14, result.fresh_range);
+        assert_eq!(
+            vec![3..=5, 10..=20],
+            result.ranges.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn touching() {
+        let lines: Vec<String> = ["5-10", "11-20", "", "10", "11"].map(String::from).to_vec();
+
+        let separate = fresh(lines.clone(), false);
+        assert_eq!(2, separate.ranges.range_count());
+        assert_eq!(16, separate.fresh_range);
+        assert_eq!(2, separate.fresh_ids);
+
+        let coalesced = fresh(lines, true);
+        assert_eq!(vec![5..=20], coalesced.ranges.iter().collect::<Vec<_>>());
+        assert_eq!(16, coalesced.fresh_range);
+        assert_eq!(2, coalesced.fresh_ids);
+    }
+
+    #[test]
+    fn overflow() {
+        let lines = vec![
+            format!("0-{}", usize::MAX),
+            format!("{}-{}", usize::MAX - 1, usize::MAX),
+            String::new(),
+            usize::MAX.to_string(),
+        ];
+        let result = fresh(lines, true);
+        assert_eq!(usize::MAX as u128 + 1, result.fresh_range);
+        assert_eq!(1, result.fresh_ids);
+    }
+
+    #[test]
+    fn streaming() {
+        // lines are generated on demand and never collected
+        let ranges = (0..1000).map(|i| format!("{}-{}", i * 10, i * 10 + 4));
+        let ids = (0..10_000).map(|id| id.to_string());
+        let lines = ranges.chain([String::new()]).chain(ids);
+
+        let result = fresh(lines, false);
+        assert_eq!(5000, result.fresh_ids);
+        assert_eq!(5000, result.fresh_range);
+        assert_eq!(1000, result.ranges.range_count());
+    }
+
+    #[test]
+    fn lookup() {
+        let ranges = merge_ranges(["10-20".to_string(), "3-5".to_string()], false);
+        assert!(!ranges.contains(0));
+        assert!(ranges.contains(3));
+        assert!(ranges.contains(5));
+        assert!(!ranges.contains(6));
+        assert!(ranges.contains(10));
+        assert!(ranges.contains(20));
+        assert!(!ranges.contains(21));
+        assert!(!merge_ranges([], false).contains(1));
+    }
+
+    #[test]
+    fn queries() {
+        let ranges = merge_ranges(["3-5", "10-14", "16-20", "12-18"].map(String::from), false);
+        let input = "1\n5\n\n8\n11\n17\n32\n0-100\n4-11\n20-3\nfive\n1-x\n3-3\n";
+        let mut output = vec![];
+        serve(&ranges, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            "spoiled\nfresh\nspoiled\nfresh\nfresh\nspoiled\n14\n4\n\
+             error: invalid range \"20-3\"\nerror: invalid id \"five\"\n\
+             error: invalid range \"1-x\"\n1\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn socket() {
+        let ranges = merge_ranges(["3-5", "10-14"].map(String::from), false);
+        let (server, client) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || serve_stream(&ranges, server));
+
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut writer = &client;
+        let mut ask = |query: &str| {
+            writeln!(writer, "{query}").unwrap();
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            reply.trim_end().to_string()
+        };
+        assert_eq!("fresh", ask("4"));
+        assert_eq!("spoiled", ask("6"));
+        assert_eq!("4", ask("4-11"));
+
+        client.shutdown(Shutdown::Write).unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn stale_socket() {
+        let path = env::temp_dir().join(format!("day5_test_{}.sock", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        // a live socket is left alone, so binding over it still fails
+        let live = UnixListener::bind(path).unwrap();
+        remove_stale_socket(path).unwrap();
+        assert!(UnixListener::bind(path).is_err());
+
+        // once its listener has gone the file is stale and gets replaced
+        drop(live);
+        remove_stale_socket(path).unwrap();
+        let listener = UnixListener::bind(path).unwrap();
+        drop(SocketFile(path));
+        assert!(fs::symlink_metadata(path).is_err());
+        drop(listener);
+
+        // other files are never removed
+        fs::write(path, "not a socket").unwrap();
+        remove_stale_socket(path).unwrap();
+        assert!(UnixListener::bind(path).is_err());
+        fs::remove_file(path).unwrap();
     }
 }
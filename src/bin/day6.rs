@@ -1,14 +1,21 @@
+use anyhow::{Error, anyhow, bail};
 use std::env;
 use std::fs::File;
 use std::io::{BufRead, BufReader, stdin};
+use std::num::{IntErrorKind, ParseIntError};
+use std::ops::Range;
 
-fn main() {
+fn main() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
-    let filename = if args.len() < 2 {
-        "src/bin/day6.txt"
-    } else {
-        &args[1]
-    };
+    let filename = args
+        .iter()
+        .skip(1)
+        .find(|a| !a.starts_with("--"))
+        .map(String::as_str)
+        .unwrap_or("src/bin/day6.txt");
+    // print every problem as an expression in both readings
+    let json = args.iter().any(|a| a == "--json");
+    let explain = json || args.iter().any(|a| a == "--explain");
 
     let file: Box<dyn BufRead> = if filename == "-" {
         Box::new(stdin().lock())
@@ -19,88 +26,341 @@ fn main() {
     };
     let text = file.lines().map(Result::unwrap).collect::<Vec<_>>();
 
-    let result = homework2(text);
+    if explain {
+        let problems = parse(&text)?;
+        if json {
+            println!("[");
+            for (i, problem) in problems.iter().enumerate() {
+                let comma = if i + 1 < problems.len() { "," } else { "" };
+                println!("  {}{comma}", problem.to_json());
+            }
+            println!("]");
+        } else {
+            for (i, problem) in problems.iter().enumerate() {
+                println!("problem {i} (columns {:?})", problem.columns);
+                println!("  rows:    {}", problem.expression(Problem::row_operands));
+                println!(
+                    "  columns: {}",
+                    problem.expression(Problem::column_operands)
+                );
+            }
+        }
+        return Ok(());
+    }
 
-    println!("{result}");
+    println!("rows: {}", homework(text.clone())?);
+    println!("columns: {}", homework2(text)?);
+
+    Ok(())
 }
 
-fn homework(lines: Vec<String>) -> u64 {
-    let mut split_lines: Vec<_> = lines
-        .iter()
-        .map(|l| {
-            l.split(' ')
-                .filter(|l| !l.is_empty())
-                .map(str::to_owned)
-                .collect()
+/// Order in which a problem's operands are combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Associativity {
+    /// `a op b op c` is `(a op b) op c`
+    Left,
+    /// `a op b op c` is `a op (b op c)`
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Max,
+    Min,
+    Power,
+}
+
+/// Every operator the worksheet may use, by symbol, with its evaluation order. All of them combine
+/// operands left to right except `^`, which is right-associative as usual, so `2 ^ 3 ^ 2` is
+/// `2 ^ 9`.
+const OPERATORS: [(&str, Operator, Associativity); 7] = [
+    ("+", Operator::Add, Associativity::Left),
+    ("-", Operator::Subtract, Associativity::Left),
+    ("*", Operator::Multiply, Associativity::Left),
+    ("/", Operator::Divide, Associativity::Left),
+    ("max", Operator::Max, Associativity::Left),
+    ("min", Operator::Min, Associativity::Left),
+    ("^", Operator::Power, Associativity::Right),
+];
+
+impl Operator {
+    fn from_symbol(symbol: &str) -> Option<Operator> {
+        OPERATORS
+            .iter()
+            .find(|(s, _, _)| *s == symbol)
+            .map(|(_, op, _)| *op)
+    }
+
+    fn entry(self) -> &'static (&'static str, Operator, Associativity) {
+        OPERATORS.iter().find(|(_, op, _)| *op == self).unwrap()
+    }
+
+    fn symbol(self) -> &'static str {
+        self.entry().0
+    }
+
+    fn associativity(self) -> Associativity {
+        self.entry().2
+    }
+
+    /// Checked `a op b`; integer division rounds down.
+    fn apply(self, a: u128, b: u128) -> Result<u128, &'static str> {
+        match self {
+            Operator::Add => a.checked_add(b).ok_or("overflowed"),
+            Operator::Subtract => a.checked_sub(b).ok_or("went negative"),
+            Operator::Multiply => a.checked_mul(b).ok_or("overflowed"),
+            Operator::Divide => a.checked_div(b).ok_or("divided by zero"),
+            Operator::Max => Ok(a.max(b)),
+            Operator::Min => Ok(a.min(b)),
+            Operator::Power => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_pow(b))
+                .ok_or("overflowed"),
+        }
+    }
+}
+
+/// One problem on the worksheet: a block of columns between all-blank separator columns, with its
+/// operator somewhere in the block on the last row.
+#[derive(Debug, PartialEq, Eq)]
+struct Problem {
+    operator: Operator,
+    /// the problem's slice of each number row, padded with spaces to the width of the block
+    operands: Vec<String>,
+    /// the worksheet columns the problem occupies
+    columns: Range<usize>,
+}
+
+impl Problem {
+    /// Reads each row as a number, top to bottom. Rows with no digits in this problem are skipped.
+    fn row_operands(&self) -> anyhow::Result<Vec<u128>> {
+        self.operands
+            .iter()
+            .map(|row| row.trim())
+            .filter(|row| !row.is_empty())
+            .map(|row| self.number(row))
+            .collect()
+    }
+
+    /// Reads each column top to bottom as a number, rightmost column first. A column with no
+    /// digits, such as one only spanned by a wide operator, is skipped; a column of `0`s is the
+    /// operand zero.
+    fn column_operands(&self) -> anyhow::Result<Vec<u128>> {
+        (0..self.columns.len())
+            .rev()
+            .map(|col| {
+                self.operands
+                    .iter()
+                    .map(|row| row.as_bytes()[col] as char)
+                    .filter(|c| *c != ' ')
+                    .collect::<String>()
+            })
+            .filter(|digits| !digits.is_empty())
+            .map(|digits| self.number(&digits))
+            .collect()
+    }
+
+    fn number(&self, digits: &str) -> anyhow::Result<u128> {
+        digits.parse().map_err(|e: ParseIntError| match e.kind() {
+            IntErrorKind::PosOverflow => anyhow!(
+                "Operand {digits} in columns {:?} does not fit in 128 bits",
+                self.columns
+            ),
+            _ => anyhow!(
+                "Operand {digits:?} in columns {:?} is not a single number",
+                self.columns
+            ),
         })
-        .collect();
+    }
 
-    let ops: Vec<_> = split_lines.pop().unwrap();
+    fn evaluate(&self, operands: &[u128]) -> anyhow::Result<u128> {
+        let operator = self.operator;
+        let result = match operator.associativity() {
+            Associativity::Left => operands
+                .iter()
+                .copied()
+                .map(Ok)
+                .reduce(|acc, n| operator.apply(acc?, n?)),
+            Associativity::Right => operands
+                .iter()
+                .rev()
+                .copied()
+                .map(Ok)
+                .reduce(|acc, n| operator.apply(n?, acc?)),
+        };
+        match result {
+            Some(Ok(value)) => Ok(value),
+            Some(Err(reason)) => bail!(
+                "Problem {} {operands:?} in columns {:?} {reason}",
+                operator.symbol(),
+                self.columns
+            ),
+            None => bail!("Problem in columns {:?} has no operands", self.columns),
+        }
+    }
 
-    let mut result = 0;
+    /// One reading of the problem as an expression, e.g. `4 + 431 + 623 = 1058`.
+    fn expression(&self, read: fn(&Problem) -> anyhow::Result<Vec<u128>>) -> String {
+        let operands = match read(self) {
+            Ok(operands) => operands,
+            Err(e) => return format!("error: {e}"),
+        };
+        let lhs = operands
+            .iter()
+            .map(u128::to_string)
+            .collect::<Vec<_>>()
+            .join(&format!(" {} ", self.operator.symbol()));
+        match self.evaluate(&operands) {
+            Ok(value) => format!("{lhs} = {value}"),
+            Err(e) => format!("{lhs} = error: {e}"),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"columns": [{}, {}], "operator": {}, "rows": {}, "cephalopod": {}}}"#,
+            self.columns.start,
+            self.columns.end,
+            json_string(self.operator.symbol()),
+            self.reading_json(Problem::row_operands),
+            self.reading_json(Problem::column_operands),
+        )
+    }
 
-    for (i, op) in ops.iter().enumerate() {
-        let column = split_lines
+    fn reading_json(&self, read: fn(&Problem) -> anyhow::Result<Vec<u128>>) -> String {
+        let operands = match read(self) {
+            Ok(operands) => operands,
+            Err(e) => return format!(r#"{{"error": {}}}"#, json_string(&e.to_string())),
+        };
+        let list = operands
             .iter()
-            .map(|v| v.get(i).unwrap().parse::<u64>().unwrap());
-        let partial = if op == "+" {
-            column.sum::<u64>()
-        } else {
-            column.product()
+            .map(u128::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let outcome = match self.evaluate(&operands) {
+            Ok(value) => format!(r#""result": {value}"#),
+            Err(e) => format!(r#""error": {}"#, json_string(&e.to_string())),
         };
-        // println!("{i}\t{op}\t{partial}");
-        result += partial;
+        format!(r#"{{"operands": [{list}], {outcome}}}"#)
     }
-    result
 }
 
-fn homework2(mut lines: Vec<String>) -> u64 {
-    let ops = lines.pop().unwrap();
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
 
-    let mut result = 0;
-    let mut partial = 0u64;
-    let mut op = '?';
+/// Splits the worksheet into problems. Columns that are blank on every row, including the
+/// operator row, separate one problem from the next.
+fn parse(lines: &[String]) -> anyhow::Result<Vec<Problem>> {
+    let Some((ops, rows)) = lines.split_last() else {
+        return Ok(vec![]);
+    };
+    // columns are sliced by byte, so only ASCII lines up
+    for (i, line) in lines.iter().enumerate() {
+        if let Some((col, c)) = line.chars().enumerate().find(|(_, c)| !c.is_ascii()) {
+            bail!("Unexpected {c:?} in row {i}, column {col}");
+        }
+    }
+    let width = lines.iter().map(String::len).max().unwrap_or(0);
+    let padded = rows
+        .iter()
+        .map(|row| format!("{row:<width$}"))
+        .collect::<Vec<_>>();
+    let ops = format!("{ops:<width$}");
 
-    for col in 0..ops.len() {
-        let maybe_op = ops.chars().nth(col).unwrap();
-        if maybe_op != ' ' {
-            println!("updating {result} += {partial}");
-            result += partial;
-            op = maybe_op;
-            partial = if op == '+' { 0 } else { 1 };
+    for (i, row) in padded.iter().enumerate() {
+        if let Some(col) = row.bytes().position(|b| b != b' ' && !b.is_ascii_digit()) {
+            bail!("Unexpected {:?} in row {i}, column {col}", &row[col..=col]);
         }
-        let mut operand = 0u64;
-        for line in &lines {
-            if let Ok(digit) = line
-                .chars()
-                .nth(col)
-                .unwrap_or(' ')
-                .to_string()
-                .parse::<u64>()
-            {
-                operand = operand * 10 + digit;
-            }
+    }
+
+    let blank = |col: usize| {
+        ops.as_bytes()[col] == b' ' && padded.iter().all(|row| row.as_bytes()[col] == b' ')
+    };
+
+    let mut problems = vec![];
+    let mut col = 0;
+    while col < width {
+        if blank(col) {
+            col += 1;
+            continue;
         }
-        if operand != 0 {
-            if op == '+' {
-                partial += operand;
-            } else {
-                partial *= operand;
-            }
+        let start = col;
+        while col < width && !blank(col) {
+            col += 1;
         }
-        println!("{op}:  {operand}\t{partial}\t{result}");
+        let columns = start..col;
+
+        let mut symbols = ops[columns.clone()].split_whitespace();
+        let (Some(symbol), None) = (symbols.next(), symbols.next()) else {
+            bail!("Expected exactly one operator in columns {columns:?}");
+        };
+        let Some(operator) = Operator::from_symbol(symbol) else {
+            let column = start + ops[columns].find(symbol).unwrap();
+            bail!("Unknown operator {symbol:?} in column {column}");
+        };
+
+        problems.push(Problem {
+            operator,
+            operands: padded
+                .iter()
+                .map(|row| row[columns.clone()].to_string())
+                .collect(),
+            columns,
+        });
     }
+    Ok(problems)
+}
+
+/// Reads each problem's numbers row by row.
+fn homework(lines: Vec<String>) -> anyhow::Result<u128> {
+    total(&parse(&lines)?, Problem::row_operands)
+}
 
-    // for (i, op) in ops.iter().enumerate() {
-    //
-    // }
-    result + partial
+/// Reads each problem's numbers cephalopod-style, one per column.
+fn homework2(lines: Vec<String>) -> anyhow::Result<u128> {
+    total(&parse(&lines)?, Problem::column_operands)
+}
+
+fn total(
+    problems: &[Problem],
+    read: fn(&Problem) -> anyhow::Result<Vec<u128>>,
+) -> anyhow::Result<u128> {
+    problems.iter().try_fold(0u128, |sum, problem| {
+        let value = problem.evaluate(&read(problem)?)?;
+        sum.checked_add(value).ok_or_else(|| {
+            anyhow!(
+                "Worksheet total overflowed at columns {:?}",
+                problem.columns
+            )
+        })
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use crate::*;
 
+    const EXAMPLE_INPUT: &str = r"123 328  51 64
+ 45 64  387 23
+  6 98  215 314
+*   +   *   +  ";
+
     #[test]
     fn example() {
         let lines = r"123 328  51 64
@@ -111,7 +371,7 @@ mod tests {
             .map(String::from)
             .collect();
         let result = homework(lines);
-        assert_eq!(4277556, result);
+        assert_eq!(4277556, result.unwrap());
     }
 
     #[test]
@@ -124,6 +384,200 @@ mod tests {
             .map(String::from)
             .collect();
         let result = homework2(lines);
-        assert_eq!(3263827, result);
+        assert_eq!(3263827, result.unwrap());
+    }
+
+    #[test]
+    fn parse_example() {
+        let lines = EXAMPLE_INPUT
+            .split('\n')
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let problems = parse(&lines).unwrap();
+        assert_eq!(4, problems.len());
+        assert_eq!(
+            Problem {
+                operator: Operator::Multiply,
+                operands: vec!["123".to_string(), " 45".to_string(), "  6".to_string()],
+                columns: 0..3,
+            },
+            problems[0]
+        );
+        assert_eq!(vec![328, 64, 98], problems[1].row_operands().unwrap());
+        assert_eq!(vec![8, 248, 369], problems[1].column_operands().unwrap());
+        assert_eq!(12..15, problems[3].columns);
+        assert_eq!(vec![64, 23, 314], problems[3].row_operands().unwrap());
+        assert_eq!(vec![4, 431, 623], problems[3].column_operands().unwrap());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let lines = ["12 3", "4x 5", "+  *"].map(String::from);
+        assert!(parse(&lines).is_err());
+
+        let lines = ["12 3", "45 5", "++ *"].map(String::from);
+        assert!(parse(&lines).is_err());
+
+        // a gap inside a problem's block splits an operand in two
+        let lines = ["1 2", "345", "+  "].map(String::from);
+        let error = homework(lines.to_vec()).unwrap_err();
+        assert_eq!(
+            "Operand \"1 2\" in columns 0..3 is not a single number",
+            error.to_string()
+        );
+
+        let lines = ["12 é", "45 5", "+  *"].map(String::from);
+        let error = parse(&lines).unwrap_err();
+        assert_eq!("Unexpected 'é' in row 0, column 3", error.to_string());
+        let lines = ["12 3", "45 5", "+  ×"].map(String::from);
+        assert!(parse(&lines).is_err());
+    }
+
+    #[test]
+    fn huge_columns() {
+        let max = u64::MAX.to_string();
+        let lines = vec![max.clone(), max.clone(), "*".to_string()];
+        assert_eq!(
+            u64::MAX as u128 * u64::MAX as u128,
+            homework(lines).unwrap()
+        );
+
+        let lines = vec![max.clone(), max.clone(), max, "*".to_string()];
+        let error = homework(lines).unwrap_err().to_string();
+        assert!(error.contains("columns 0..20"), "{error}");
+
+        let lines = vec!["9".repeat(40), "+".to_string()];
+        let error = homework(lines).unwrap_err().to_string();
+        assert!(error.ends_with("does not fit in 128 bits"), "{error}");
+
+        // 38 stacked nines is the largest all-nines column that fits in a u128
+        let mut lines = vec!["99".to_string(); 38];
+        lines.push("+ ".to_string());
+        assert_eq!(2 * (10u128.pow(38) - 1), homework2(lines.clone()).unwrap());
+        lines[38] = "* ".to_string();
+        let error = homework2(lines.clone()).unwrap_err().to_string();
+        assert!(error.contains("columns 0..2"), "{error}");
+        lines.insert(0, "99".to_string());
+        assert!(homework2(lines).is_err());
+
+        // each problem fits, but the total doesn't
+        let lines = vec![format!("{} 1", u128::MAX), format!("+{}+", " ".repeat(39))];
+        let error = homework(lines).unwrap_err().to_string();
+        assert!(
+            error.contains("total overflowed at columns 40..41"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn operators() {
+        let lines = r"20 100 2 7   3   2
+ 7  10 5 4   9   3
+ 3   5 1 8   1   2
+-  /   ^ max min ^"
+            .split('\n')
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let problems = parse(&lines).unwrap();
+        let values = problems
+            .iter()
+            .map(|p| p.evaluate(&p.row_operands().unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        // 2 ^ 5 ^ 1 = 32 and 2 ^ 3 ^ 2 = 2 ^ 9
+        assert_eq!(vec![10, 2, 32, 8, 1, 512], values);
+    }
+
+    #[test]
+    fn operator_errors() {
+        let lines = ["1 2", "3 4", "+ %"].map(String::from);
+        let error = homework(lines.to_vec()).unwrap_err().to_string();
+        assert_eq!("Unknown operator \"%\" in column 2", error);
+
+        let lines = ["1", "3", "-"].map(String::from);
+        let error = homework(lines.to_vec()).unwrap_err().to_string();
+        assert!(error.ends_with("went negative"), "{error}");
+
+        let lines = ["1", "0", "/"].map(String::from);
+        let error = homework(lines.to_vec()).unwrap_err().to_string();
+        assert!(error.ends_with("divided by zero"), "{error}");
+    }
+
+    #[test]
+    fn zeros() {
+        let lines = r"10 5
+20 0
+*  +"
+            .split('\n')
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let problems = parse(&lines).unwrap();
+        assert_eq!(vec![10, 20], problems[0].row_operands().unwrap());
+        assert_eq!(vec![0, 12], problems[0].column_operands().unwrap());
+        assert_eq!(vec![5, 0], problems[1].row_operands().unwrap());
+        assert_eq!(vec![50], problems[1].column_operands().unwrap());
+        assert_eq!(200 + 5, homework(lines.clone()).unwrap());
+        assert_eq!(50, homework2(lines).unwrap());
+
+        let lines = ["0", "0", "+"].map(String::from).to_vec();
+        assert_eq!(0, homework(lines.clone()).unwrap());
+        assert_eq!(0, homework2(lines).unwrap());
+    }
+
+    #[test]
+    fn blank_columns_are_not_zero() {
+        // the operator is wider than the numbers, leaving two digitless columns in the problem
+        let lines = r"5
+7
+min"
+        .split('\n')
+        .map(String::from)
+        .collect::<Vec<_>>();
+        let problems = parse(&lines).unwrap();
+        assert_eq!(0..3, problems[0].columns);
+        assert_eq!(vec![57], problems[0].column_operands().unwrap());
+        assert_eq!(57, homework2(lines.clone()).unwrap());
+        assert_eq!(5, homework(lines).unwrap());
+    }
+
+    #[test]
+    fn explain() {
+        let lines = EXAMPLE_INPUT
+            .split('\n')
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let problems = parse(&lines).unwrap();
+        assert_eq!(
+            "123 * 45 * 6 = 33210",
+            problems[0].expression(Problem::row_operands)
+        );
+        assert_eq!(
+            "356 * 24 * 1 = 8544",
+            problems[0].expression(Problem::column_operands)
+        );
+        assert_eq!(
+            "4 + 431 + 623 = 1058",
+            problems[3].expression(Problem::column_operands)
+        );
+        assert_eq!(
+            r#"{"columns": [12, 15], "operator": "+", "rows": {"operands": [64, 23, 314], "result": 401}, "cephalopod": {"operands": [4, 431, 623], "result": 1058}}"#,
+            problems[3].to_json()
+        );
+
+        let lines = ["1", "3", "-"].map(String::from);
+        let problems = parse(&lines).unwrap();
+        assert_eq!(
+            "1 - 3 = error: Problem - [1, 3] in columns 0..1 went negative",
+            problems[0].expression(Problem::row_operands)
+        );
+        assert!(
+            problems[0]
+                .to_json()
+                .contains(r#""rows": {"operands": [1, 3], "error": "Problem"#)
+        );
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(r#""a\"b\\c\u000a""#, json_string("a\"b\\c\n"));
     }
 }
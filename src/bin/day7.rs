@@ -1,13 +1,75 @@
+use crate::Direction::{East, North, South, West};
+use anyhow::{Error, bail};
+use rand::Rng;
+use simple_svg::{Group, Line, Polygon, Rect, Shape, Sstyle, Svg, Widget, svg_out};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fmt::{self, Display, Formatter, Write};
 use std::fs::File;
 use std::io::{BufRead, BufReader, stdin};
 
-fn main() {
+fn main() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
-    let filename = if args.len() < 2 {
-        "src/bin/day7.txt"
+    let filename = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(i, a)| {
+            !a.starts_with("--")
+                && !["--modulo", "--svg", "--graph", "--through", "--sample"]
+                    .contains(&args[i - 1].as_str())
+        })
+        .map(|(_, a)| a.as_str())
+        .unwrap_or("src/bin/day7.txt");
+    // use the general beam engine, which understands mirrors, blockers and deflectors
+    let engine = args.iter().any(|a| a == "--engine");
+    // split beams bounce off the sides of the manifold instead of leaving it
+    let sides = if args.iter().any(|a| a == "--reflect") {
+        Sides::Reflect
     } else {
-        &args[1]
+        Sides::Exit
+    };
+    // draw the beams through the manifold to this SVG file
+    let svg = args
+        .iter()
+        .position(|a| a == "--svg")
+        .map(|pos| args.get(pos + 1).expect("Missing SVG file").as_str());
+    // print the splitter graph as `dot` or `json` instead of the counts
+    let graph = args
+        .iter()
+        .position(|a| a == "--graph")
+        .map(|pos| args.get(pos + 1).expect("Missing graph format").as_str());
+    // count the timelines through the splitter at `<row>,<col>`
+    let through = match args.iter().position(|a| a == "--through") {
+        Some(pos) => {
+            let at = args.get(pos + 1).and_then(|at| at.split_once(','));
+            let Some((Ok(row), Ok(col))) = at.map(|(r, c)| (r.parse(), c.parse())) else {
+                bail!("--through needs a splitter position as <row>,<col>");
+            };
+            Some((row, col))
+        }
+        None => None,
+    };
+    // list every timeline as its left and right choices
+    let enumerate = args.iter().any(|a| a == "--enumerate");
+    // print this many timelines drawn uniformly at random
+    let sample = match args.iter().position(|a| a == "--sample") {
+        Some(pos) => match args.get(pos + 1).map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) => Some(n),
+            _ => bail!("--sample needs a number of timelines"),
+        },
+        None => None,
+    };
+    let counting = if let Some(pos) = args.iter().position(|a| a == "--modulo") {
+        let modulus = args.get(pos + 1).map(|m| m.parse::<u128>());
+        let Some(Ok(modulus @ 2..)) = modulus else {
+            bail!("--modulo needs a modulus of at least 2");
+        };
+        Counting::Modulo(modulus)
+    } else if args.iter().any(|a| a == "--u64") {
+        Counting::U64
+    } else {
+        Counting::U128
     };
 
     let file: Box<dyn BufRead> = if filename == "-" {
@@ -18,63 +80,973 @@ fn main() {
         ))
     };
     let text = file.lines().map(Result::unwrap).collect::<Vec<_>>();
+    let manifold = || Manifold::parse(&text).map(|m| Manifold { sides, ..m });
+
+    if let Some(svg) = svg {
+        let manifold = manifold()?;
+        // widths need real volumes, not residues, so they are always counted exactly; a looping
+        // manifold or one whose counts overflow is drawn at one width
+        let flows = manifold
+            .flows(Counting::U128)
+            .inspect_err(|e| eprintln!("{e}"))
+            .ok();
+        to_svg(svg, &manifold, &manifold.simulate(), flows.as_ref())?;
+    }
+
+    if let Some(format) = graph {
+        let graph = manifold()?.graph()?;
+        match format {
+            "dot" => print!("{}", graph.to_dot()),
+            "json" => println!("{}", graph.to_json()),
+            _ => bail!("Unknown graph format {format:?}, expected dot or json"),
+        }
+        return Ok(());
+    }
 
-    let result = teleport2(text);
+    if enumerate || sample.is_some() {
+        let graph = manifold()?.graph()?;
+        if enumerate {
+            for timeline in graph.timelines() {
+                println!("{timeline}");
+            }
+        }
+        if let Some(n) = sample {
+            let sampler = Sampler::new(&graph)?;
+            let mut rng = rand::rng();
+            for _ in 0..n {
+                println!("{}", sampler.sample(&mut rng));
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some((row, col)) = through {
+        let graph = manifold()?.graph()?;
+        let mut paths = 0;
+        for direction in [South, North] {
+            let splitter = Node::Splitter(Beam {
+                row,
+                col,
+                direction,
+            });
+            if let Some(node) = graph.find(splitter) {
+                paths = counting.add(paths, graph.paths_through(node, counting)?)?;
+            }
+        }
+        println!("through {row},{col}: {paths}");
+        return Ok(());
+    }
 
-    println!("{result}");
+    if engine {
+        let manifold = manifold()?;
+        println!("splits: {}", manifold.simulate().splits.len());
+        println!("timelines: {}", manifold.timelines(counting)?);
+        if let Some((col, paths)) = manifold.graph()?.busiest_exit(counting)? {
+            println!("busiest exit: column {col}, {paths} timelines");
+        }
+        return Ok(());
+    }
+
+    println!("splits: {}", teleport(text.clone(), sides));
+    println!("timelines: {}", teleport2(text, sides, counting)?);
+
+    Ok(())
 }
 
-fn teleport(lines: Vec<String>) -> u64 {
+/// How timeline counts are accumulated. They double with every row of splitters a beam passes,
+/// so tall manifolds need either a wide type or modular arithmetic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Counting {
+    /// exact, failing if a count passes `u64::MAX`
+    U64,
+    /// exact, failing if a count passes `u128::MAX`
+    U128,
+    /// modulo the given number, usually a large prime
+    Modulo(u128),
+}
+
+impl Counting {
+    fn add(self, a: u128, b: u128) -> anyhow::Result<u128> {
+        match self {
+            Counting::U64 => match a.checked_add(b) {
+                Some(sum) if sum <= u64::MAX as u128 => Ok(sum),
+                _ => bail!("Timeline count overflowed u64"),
+            },
+            Counting::U128 => match a.checked_add(b) {
+                Some(sum) => Ok(sum),
+                None => bail!("Timeline count overflowed u128"),
+            },
+            // a and b are already reduced, so a + b < 2m; subtract before adding so it can't wrap
+            Counting::Modulo(m) => Ok(if a >= m - b { a - (m - b) } else { a + b }),
+        }
+    }
+
+    fn mul(self, a: u128, b: u128) -> anyhow::Result<u128> {
+        match self {
+            Counting::U64 => match a.checked_mul(b) {
+                Some(product) if product <= u64::MAX as u128 => Ok(product),
+                _ => bail!("Timeline count overflowed u64"),
+            },
+            Counting::U128 => match a.checked_mul(b) {
+                Some(product) => Ok(product),
+                None => bail!("Timeline count overflowed u128"),
+            },
+            // double and add, so nothing wider than the modulus is ever needed
+            Counting::Modulo(_) => {
+                let (mut product, mut addend, mut b) = (0, a, b);
+                while b > 0 {
+                    if b & 1 == 1 {
+                        product = self.add(product, addend)?;
+                    }
+                    addend = self.add(addend, addend)?;
+                    b >>= 1;
+                }
+                Ok(product)
+            }
+        }
+    }
+
+    /// A single timeline.
+    fn one(self) -> u128 {
+        match self {
+            Counting::Modulo(m) => 1 % m,
+            _ => 1,
+        }
+    }
+}
+
+/// What happens to half of a split beam that would go off the side of the manifold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Sides {
+    /// it leaves the manifold, and still counts as a timeline
+    Exit,
+    /// it bounces off the side and joins the other half, unless that is off the manifold too
+    Reflect,
+}
+
+/// Columns the two halves of a beam split at column `i` carry on down, `None` where one leaves
+/// the manifold.
+fn split_targets(i: usize, width: usize, sides: Sides) -> [Option<usize>; 2] {
+    let left = i.checked_sub(1);
+    let right = (i + 1 < width).then_some(i + 1);
+    match sides {
+        Sides::Exit => [left, right],
+        Sides::Reflect => [left.or(right), right.or(left)],
+    }
+}
+
+/// Counts the splitters a beam actually reaches.
+fn teleport(lines: Vec<String>, sides: Sides) -> u64 {
     let first = &lines[0];
     let beam = first.find('S').unwrap();
-    println!("Beam: {}", beam);
 
+    let width = lines.iter().map(String::len).max().unwrap_or(0);
     let mut splits = 0;
-    let mut beams = bit_vec::BitVec::from_elem(first.len(), false);
+    let mut beams = bit_vec::BitVec::from_elem(width, false);
     beams.set(beam, true);
 
     for line in lines.iter().skip(1) {
-        line.chars()
-            .enumerate()
-            .filter(|(_i, c)| *c == '^')
-            .for_each(|(i, _)| {
-                beams.set(i - 1, true);
-                beams.set(i, false);
-                beams.set(i + 1, true);
-                splits += 1;
-            });
-        println!("Bitvec: {}", beams);
+        for (i, _c) in line.chars().enumerate().filter(|(_i, c)| *c == '^') {
+            if !beams[i] {
+                continue;
+            }
+            beams.set(i, false);
+            for target in split_targets(i, width, sides).into_iter().flatten() {
+                beams.set(target, true);
+            }
+            splits += 1;
+        }
     }
 
     splits
 }
 
-fn teleport2(lines: Vec<String>) -> u64 {
+/// Counts timelines, including those that leave the manifold through its sides.
+fn teleport2(lines: Vec<String>, sides: Sides, counting: Counting) -> anyhow::Result<u128> {
     let first = &lines[0];
     let beam = first.find('S').unwrap();
-    println!("Beam: {}", beam);
 
-    let mut beams = vec![0u64; first.len()];
-    beams[beam] = 1;
+    let width = lines.iter().map(String::len).max().unwrap_or(0);
+    let mut beams = vec![0u128; width];
+    beams[beam] = counting.one();
+    let mut exited = 0;
 
     for line in lines.iter().skip(1) {
-        line.chars()
+        for (i, _c) in line.chars().enumerate().filter(|(_i, c)| *c == '^') {
+            let arriving = beams[i];
+            beams[i] = 0;
+            for target in split_targets(i, width, sides) {
+                match target {
+                    Some(t) => beams[t] = counting.add(beams[t], arriving)?,
+                    None => exited = counting.add(exited, arriving)?,
+                }
+            }
+        }
+    }
+
+    beams
+        .iter()
+        .try_fold(exited, |sum, b| counting.add(sum, *b))
+}
+
+/// SVG style: `y` (the row) increases downwards, so South is down the manifold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Direction {
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Direction {
+    const ALL: [Direction; 4] = [North, South, East, West];
+
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    #[inline]
+    fn vertical(self) -> bool {
+        matches!(self, North | South)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Tile {
+    /// `.`, and also `S` once the beam has left it
+    Empty,
+    /// `^` splits a vertical beam into two beams in the cells either side, carrying on in the
+    /// same direction; a beam arriving from the side is stopped
+    Splitter,
+    /// `/` reflects South to West, North to East, East to North and West to South
+    Mirror,
+    /// `\` reflects South to East, North to West, East to South and West to North
+    BackMirror,
+    /// `#` stops the beam
+    Blocker,
+    /// `<` turns every beam West
+    Left,
+    /// `>` turns every beam East
+    Right,
+}
+
+/// A beam in a cell, travelling in a direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Beam {
+    row: usize,
+    col: usize,
+    direction: Direction,
+}
+
+/// Where a beam goes after interacting with a tile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    /// into another cell of the manifold
+    Beam(Beam),
+    /// off the edge of the manifold, from the cell it was in
+    Exit(Beam),
+}
+
+#[derive(Clone, Debug)]
+struct Manifold {
+    tiles: Vec<Vec<Tile>>,
+    width: usize,
+    start: Beam,
+    sides: Sides,
+}
+
+#[derive(Debug, Default)]
+struct Simulation {
+    /// splitters reached by at least one beam, as `(row, col)`
+    splits: HashSet<(usize, usize)>,
+    /// every cell and direction a beam passed through
+    visited: HashSet<Beam>,
+    /// beams that left the manifold, in the last cell before leaving
+    exits: HashSet<Beam>,
+}
+
+impl Manifold {
+    fn parse(lines: &[String]) -> anyhow::Result<Manifold> {
+        let width = lines.iter().map(String::len).max().unwrap_or(0);
+        let mut start = None;
+        let mut tiles = Vec::with_capacity(lines.len());
+        for (row, line) in lines.iter().enumerate() {
+            let mut tile_row = vec![Tile::Empty; width];
+            for (col, c) in line.chars().enumerate() {
+                tile_row[col] = match c {
+                    '.' => Tile::Empty,
+                    'S' => {
+                        if start.is_some() {
+                            bail!("Second start at row {row}, column {col}");
+                        }
+                        start = Some(Beam {
+                            row,
+                            col,
+                            direction: South,
+                        });
+                        Tile::Empty
+                    }
+                    '^' => Tile::Splitter,
+                    '/' => Tile::Mirror,
+                    '\\' => Tile::BackMirror,
+                    '#' => Tile::Blocker,
+                    '<' => Tile::Left,
+                    '>' => Tile::Right,
+                    _ => bail!("Unknown tile {c:?} at row {row}, column {col}"),
+                };
+            }
+            tiles.push(tile_row);
+        }
+        let Some(start) = start else {
+            bail!("No start S in manifold");
+        };
+        Ok(Manifold {
+            tiles,
+            width,
+            start,
+            sides: Sides::Exit,
+        })
+    }
+
+    /// The beam one cell on from `beam` in `direction`, or an exit if that is off the edge.
+    fn advance(&self, beam: Beam, direction: Direction) -> Step {
+        let Beam { row, col, .. } = beam;
+        let next = match direction {
+            North => row.checked_sub(1).map(|row| (row, col)),
+            South => (row + 1 < self.tiles.len()).then_some((row + 1, col)),
+            West => col.checked_sub(1).map(|col| (row, col)),
+            East => (col + 1 < self.width).then_some((row, col + 1)),
+        };
+        match next {
+            Some((row, col)) => Step::Beam(Beam {
+                row,
+                col,
+                direction,
+            }),
+            None => Step::Exit(Beam { direction, ..beam }),
+        }
+    }
+
+    /// Where `beam` goes after the tile in its cell has acted on it.
+    fn steps(&self, beam: Beam) -> Vec<Step> {
+        let d = beam.direction;
+        let turn = |direction| vec![self.advance(beam, direction)];
+        match self.tiles[beam.row][beam.col] {
+            Tile::Empty => turn(d),
+            Tile::Splitter if d.vertical() => {
+                let halves = [West, East].map(|side| match self.advance(beam, side) {
+                    Step::Beam(b) => Step::Beam(Beam { direction: d, ..b }),
+                    Step::Exit(b) => Step::Exit(b),
+                });
+                match (self.sides, halves) {
+                    // a half that would leave bounces off the side and follows the other
+                    (
+                        Sides::Reflect,
+                        [Step::Exit(_), inside @ Step::Beam(_)]
+                        | [inside @ Step::Beam(_), Step::Exit(_)],
+                    ) => vec![inside, inside],
+                    _ => halves.to_vec(),
+                }
+            }
+            Tile::Splitter | Tile::Blocker => vec![],
+            Tile::Mirror => turn(match d {
+                South => West,
+                North => East,
+                East => North,
+                West => South,
+            }),
+            Tile::BackMirror => turn(match d {
+                South => East,
+                North => West,
+                East => South,
+                West => North,
+            }),
+            Tile::Left => turn(West),
+            Tile::Right => turn(East),
+        }
+    }
+
+    /// Follows every beam from the start until it stops, exits, or repeats a cell and direction
+    /// it has already been through.
+    fn simulate(&self) -> Simulation {
+        let mut sim = Simulation::default();
+        let mut pending = vec![self.start];
+        while let Some(beam) = pending.pop() {
+            if !sim.visited.insert(beam) {
+                continue;
+            }
+            if self.splits(beam) {
+                sim.splits.insert((beam.row, beam.col));
+            }
+            for step in self.steps(beam) {
+                match step {
+                    Step::Beam(next) => pending.push(next),
+                    Step::Exit(exit) => {
+                        sim.exits.insert(exit);
+                    }
+                }
+            }
+        }
+        sim
+    }
+
+    #[inline]
+    fn state(&self, beam: Beam) -> usize {
+        (beam.row * self.width + beam.col) * Direction::ALL.len() + beam.direction.index()
+    }
+
+    /// Every beam reachable from the start, each before all the beams it leads to. Fails if a
+    /// beam can loop back onto itself, as there is then no such order.
+    fn topological_order(&self) -> anyhow::Result<Vec<Beam>> {
+        const UNSEEN: u8 = 0;
+        const ON_STACK: u8 = 1;
+        const DONE: u8 = 2;
+
+        let states = self.tiles.len() * self.width * Direction::ALL.len();
+        let mut mark = vec![UNSEEN; states];
+        let mut finished = vec![];
+
+        // iterative depth-first search, recording each beam once everything after it is done
+        let mut stack = vec![(self.start, self.steps(self.start), 0usize)];
+        mark[self.state(self.start)] = ON_STACK;
+        while let Some((beam, steps, next)) = stack.last_mut() {
+            let Some(step) = steps.get(*next).copied() else {
+                mark[self.state(*beam)] = DONE;
+                finished.push(*beam);
+                stack.pop();
+                continue;
+            };
+            *next += 1;
+            if let Step::Beam(child) = step {
+                let state = self.state(child);
+                match mark[state] {
+                    UNSEEN => {
+                        mark[state] = ON_STACK;
+                        stack.push((child, self.steps(child), 0));
+                    }
+                    ON_STACK => bail!(
+                        "Beam loops at row {}, column {} heading {:?}",
+                        child.row,
+                        child.col,
+                        child.direction
+                    ),
+                    _ => {}
+                }
+            }
+        }
+        finished.reverse();
+        Ok(finished)
+    }
+
+    /// Counts the distinct paths a single particle can take from the start, where each splitter
+    /// is a choice of two. A path ends when it leaves the manifold or is stopped. Fails if a
+    /// beam can loop back onto itself, as there would be infinitely many.
+    fn timelines(&self, counting: Counting) -> anyhow::Result<u128> {
+        let mut paths = vec![0u128; self.tiles.len() * self.width * Direction::ALL.len()];
+        for beam in self.topological_order()?.into_iter().rev() {
+            let steps = self.steps(beam);
+            let mut sum = if steps.is_empty() { counting.one() } else { 0 };
+            for step in steps {
+                sum = match step {
+                    Step::Exit(_) => counting.add(sum, counting.one())?,
+                    Step::Beam(child) => counting.add(sum, paths[self.state(child)])?,
+                };
+            }
+            paths[self.state(beam)] = sum;
+        }
+        Ok(paths[self.state(self.start)])
+    }
+
+    /// How many timelines pass through each reachable beam, counted forwards from the start.
+    fn flows(&self, counting: Counting) -> anyhow::Result<HashMap<Beam, u128>> {
+        let mut flows = HashMap::from([(self.start, counting.one())]);
+        for beam in self.topological_order()? {
+            let flow = flows[&beam];
+            for step in self.steps(beam) {
+                if let Step::Beam(child) = step {
+                    let entry = flows.entry(child).or_insert(0);
+                    *entry = counting.add(*entry, flow)?;
+                }
+            }
+        }
+        Ok(flows)
+    }
+}
+
+/// A point in the splitter graph where timelines start, branch or end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Start(Beam),
+    /// a splitter, reached by a vertical beam
+    Splitter(Beam),
+    /// a beam leaving the manifold, in the last cell before leaving
+    Exit(Beam),
+    /// a beam stopped by a blocker or the side of a splitter
+    Stopped(Beam),
+}
+
+impl Node {
+    fn beam(self) -> Beam {
+        match self {
+            Node::Start(b) | Node::Splitter(b) | Node::Exit(b) | Node::Stopped(b) => b,
+        }
+    }
+
+    fn kind(self) -> &'static str {
+        match self {
+            Node::Start(_) => "start",
+            Node::Splitter(_) => "splitter",
+            Node::Exit(_) => "exit",
+            Node::Stopped(_) => "stopped",
+        }
+    }
+}
+
+/// A beam segment between two nodes, `cells` moves long.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Edge {
+    from: usize,
+    to: usize,
+    cells: usize,
+}
+
+/// The manifold reduced to the nodes a beam reaches and the segments between them. Two segments
+/// may join the same pair of nodes, as each is a separate timeline.
+#[derive(Clone, Debug)]
+struct SplitterGraph {
+    /// the start is always node 0
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    /// indices into `edges` leaving each node
+    outgoing: Vec<Vec<usize>>,
+    /// node indices, each before every node it leads to
+    order: Vec<usize>,
+}
+
+impl Manifold {
+    /// Whether `beam` is split by the tile it is on.
+    fn splits(&self, beam: Beam) -> bool {
+        self.tiles[beam.row][beam.col] == Tile::Splitter && beam.direction.vertical()
+    }
+
+    /// Follows a beam through the cells that only redirect it, to the next node.
+    fn trace(&self, mut step: Step) -> (Node, usize) {
+        let mut cells = 1;
+        loop {
+            let beam = match step {
+                Step::Exit(b) => return (Node::Exit(b), cells),
+                Step::Beam(b) if self.splits(b) => return (Node::Splitter(b), cells),
+                Step::Beam(b) => b,
+            };
+            match self.steps(beam)[..] {
+                [] => return (Node::Stopped(beam), cells),
+                [next] => step = next,
+                _ => unreachable!("only splitters branch"),
+            }
+            cells += 1;
+        }
+    }
+
+    /// Builds the graph of every node reachable from the start. Fails if a beam can loop, as the
+    /// graph would then not be acyclic.
+    fn graph(&self) -> anyhow::Result<SplitterGraph> {
+        let order = self.topological_order()?;
+
+        let mut graph = SplitterGraph {
+            nodes: vec![],
+            edges: vec![],
+            outgoing: vec![],
+            order: vec![],
+        };
+        let mut index = HashMap::new();
+        let mut add = |graph: &mut SplitterGraph, node| {
+            *index.entry(node).or_insert_with(|| {
+                graph.nodes.push(node);
+                graph.outgoing.push(vec![]);
+                graph.nodes.len() - 1
+            })
+        };
+
+        let start = add(&mut graph, Node::Start(self.start));
+        let mut pending = vec![start];
+        while let Some(from) = pending.pop() {
+            let beam = match graph.nodes[from] {
+                Node::Start(b) | Node::Splitter(b) => b,
+                Node::Exit(_) | Node::Stopped(_) => continue,
+            };
+            for step in self.steps(beam) {
+                let (node, cells) = self.trace(step);
+                let known = graph.nodes.len();
+                let to = add(&mut graph, node);
+                if to == known {
+                    pending.push(to);
+                }
+                graph.outgoing[from].push(graph.edges.len());
+                graph.edges.push(Edge { from, to, cells });
+            }
+        }
+
+        // nodes sit on beams, except exits and stops which come after everything else
+        let position = order
+            .iter()
             .enumerate()
-            .filter(|(_i, c)| *c == '^')
-            .for_each(|(i, _c)| {
-                beams[i - 1] += beams[i];
-                beams[i + 1] += beams[i];
-                beams[i] = 0;
-            });
-        println!("{:?}", beams);
+            .map(|(i, &beam)| (beam, i))
+            .collect::<HashMap<_, _>>();
+        graph.order = (0..graph.nodes.len()).collect();
+        graph.order.sort_by_key(|&n| match graph.nodes[n] {
+            Node::Start(b) | Node::Splitter(b) => (0, position[&b]),
+            Node::Exit(_) | Node::Stopped(_) => (1, n),
+        });
+        Ok(graph)
+    }
+}
+
+impl SplitterGraph {
+    /// Index of `node`, if the beams reach it.
+    fn find(&self, node: Node) -> Option<usize> {
+        self.nodes.iter().position(|&n| n == node)
+    }
+
+    /// Number of timelines from the start to each node.
+    fn paths_to(&self, counting: Counting) -> anyhow::Result<Vec<u128>> {
+        let mut paths = vec![0; self.nodes.len()];
+        paths[0] = counting.one();
+        for &node in &self.order {
+            for edge in &self.outgoing[node] {
+                let to = self.edges[*edge].to;
+                paths[to] = counting.add(paths[to], paths[node])?;
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Number of timelines from each node to wherever they end.
+    fn paths_from(&self, counting: Counting) -> anyhow::Result<Vec<u128>> {
+        let mut paths = vec![0; self.nodes.len()];
+        for &node in self.order.iter().rev() {
+            if self.outgoing[node].is_empty() {
+                paths[node] = counting.one();
+            }
+            for edge in &self.outgoing[node] {
+                let to = self.edges[*edge].to;
+                paths[node] = counting.add(paths[node], paths[to])?;
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Number of timelines that pass through `node`.
+    fn paths_through(&self, node: usize, counting: Counting) -> anyhow::Result<u128> {
+        let to = self.paths_to(counting)?[node];
+        let from = self.paths_from(counting)?[node];
+        counting.mul(to, from)
+    }
+
+    /// The column whose exits receive the most timelines, and how many, preferring the leftmost
+    /// on a tie. Only meaningful with exact counting.
+    fn busiest_exit(&self, counting: Counting) -> anyhow::Result<Option<(usize, u128)>> {
+        let paths = self.paths_to(counting)?;
+        let mut columns = BTreeMap::new();
+        for (node, &n) in self.nodes.iter().zip(&paths) {
+            if let Node::Exit(beam) = node {
+                let count = columns.entry(beam.col).or_insert(0);
+                *count = counting.add(*count, n)?;
+            }
+        }
+        Ok(columns
+            .into_iter()
+            .max_by(|(c1, n1), (c2, n2)| n1.cmp(n2).then(c2.cmp(c1))))
+    }
+
+    fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph manifold {\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let Beam {
+                row,
+                col,
+                direction,
+            } = node.beam();
+            let shape = match node {
+                Node::Start(_) => "doublecircle",
+                Node::Splitter(_) => "triangle",
+                Node::Exit(_) | Node::Stopped(_) => "box",
+            };
+            dot += &format!(
+                "  n{i} [label=\"{} {row},{col} {direction:?}\" shape={shape}];\n",
+                node.kind()
+            );
+        }
+        for Edge { from, to, cells } in &self.edges {
+            dot += &format!("  n{from} -> n{to} [label={cells}];\n");
+        }
+        dot += "}\n";
+        dot
+    }
+
+    fn to_json(&self) -> String {
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let Beam { row, col, direction } = node.beam();
+                format!(
+                    r#"{{"id": {i}, "kind": "{}", "row": {row}, "col": {col}, "direction": "{direction:?}"}}"#,
+                    node.kind()
+                )
+            })
+            .collect::<Vec<_>>();
+        let edges = self
+            .edges
+            .iter()
+            .map(|Edge { from, to, cells }| {
+                format!(r#"{{"from": {from}, "to": {to}, "cells": {cells}}}"#)
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"nodes\": [\n    {}\n  ],\n  \"edges\": [\n    {}\n  ]\n}}",
+            nodes.join(",\n    "),
+            edges.join(",\n    ")
+        )
+    }
+}
+
+/// Which half of a split beam a timeline follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Choice {
+    Left,
+    Right,
+}
+
+/// A single timeline: the choice made at each splitter it meets, and the node it ends at.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Timeline {
+    choices: Vec<Choice>,
+    end: Node,
+}
+
+impl Display for Timeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for choice in &self.choices {
+            f.write_char(match choice {
+                Choice::Left => 'L',
+                Choice::Right => 'R',
+            })?;
+        }
+        let Beam { row, col, .. } = self.end.beam();
+        write!(f, " -> {} {row},{col}", self.end.kind())
+    }
+}
+
+/// Every timeline of a graph, depth first with left before right, built one at a time.
+struct Timelines<'a> {
+    graph: &'a SplitterGraph,
+    /// node, next outgoing edge to follow, and whether reaching the node was a choice
+    stack: Vec<(usize, usize, bool)>,
+    choices: Vec<Choice>,
+}
+
+impl Iterator for Timelines<'_> {
+    type Item = Timeline;
+
+    fn next(&mut self) -> Option<Timeline> {
+        let outgoing = &self.graph.outgoing;
+        let mut found = None;
+        while found.is_none() {
+            let (node, next, _) = self.stack.last_mut()?;
+            let node = *node;
+            if outgoing[node].is_empty() {
+                found = Some(Timeline {
+                    choices: self.choices.clone(),
+                    end: self.graph.nodes[node],
+                });
+            }
+            if let Some(&edge) = outgoing[node].get(*next) {
+                let chose = outgoing[node].len() > 1;
+                if chose {
+                    self.choices.push([Choice::Left, Choice::Right][*next]);
+                }
+                *next += 1;
+                self.stack.push((self.graph.edges[edge].to, 0, chose));
+            } else if let Some((_, _, true)) = self.stack.pop() {
+                self.choices.pop();
+            }
+        }
+        found
+    }
+}
+
+/// Draws timelines uniformly at random, by taking each half of a split as often as the
+/// timelines that follow it.
+struct Sampler<'a> {
+    graph: &'a SplitterGraph,
+    paths: Vec<u128>,
+}
+
+impl<'a> Sampler<'a> {
+    /// Fails if the graph has more than `u128::MAX` timelines.
+    fn new(graph: &'a SplitterGraph) -> anyhow::Result<Sampler<'a>> {
+        let paths = graph.paths_from(Counting::U128)?;
+        Ok(Sampler { graph, paths })
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Timeline {
+        let graph = self.graph;
+        let mut choices = vec![];
+        let mut node = 0;
+        while !graph.outgoing[node].is_empty() {
+            let edges = &graph.outgoing[node];
+            let mut pick = rng.random_range(0..self.paths[node]);
+            for (i, &edge) in edges.iter().enumerate() {
+                let to = graph.edges[edge].to;
+                if pick < self.paths[to] {
+                    if edges.len() > 1 {
+                        choices.push([Choice::Left, Choice::Right][i]);
+                    }
+                    node = to;
+                    break;
+                }
+                pick -= self.paths[to];
+            }
+        }
+        Timeline {
+            choices,
+            end: graph.nodes[node],
+        }
+    }
+}
+
+impl SplitterGraph {
+    /// Lists the timelines without holding more than one at a time.
+    fn timelines(&self) -> Timelines<'_> {
+        Timelines {
+            graph: self,
+            stack: vec![(0, 0, false)],
+            choices: vec![],
+        }
     }
+}
+
+/// Side of one manifold cell in the rendered SVG.
+const CELL: f64 = 10.0;
+
+/// Centre of a cell in SVG coordinates.
+fn centre(row: usize, col: usize) -> (f64, f64) {
+    ((col as f64 + 0.5) * CELL, (row as f64 + 0.5) * CELL)
+}
 
-    beams.iter().sum()
+/// Offset of one cell on in `direction`.
+fn offset(direction: Direction) -> (f64, f64) {
+    match direction {
+        North => (0.0, -CELL),
+        South => (0.0, CELL),
+        East => (CELL, 0.0),
+        West => (-CELL, 0.0),
+    }
+}
+
+/// Draws the manifold with every beam segment. Splitters that were hit are red and those never
+/// reached are grey. With `flows`, each segment is as thick as the log of the number of timelines
+/// passing along it.
+fn to_svg(
+    file: &str,
+    manifold: &Manifold,
+    sim: &Simulation,
+    flows: Option<&HashMap<Beam, u128>>,
+) -> anyhow::Result<()> {
+    let mut svg = Svg::new(
+        manifold.width as f64 * CELL,
+        manifold.tiles.len() as f64 * CELL,
+    );
+    svg.background = Some("white".to_string());
+    let mut group = Group::new();
+
+    let mut place = |svg: &mut Svg, shape, at, style: Sstyle| {
+        group.place_widget(Widget {
+            shape_id: svg.add_shape(shape),
+            at: Some(at),
+            style: Some(style),
+            ..Default::default()
+        });
+    };
+
+    // logarithmic, as counts double with every row of splitters
+    let widest = flows
+        .and_then(|f| f.values().max())
+        .map_or(1.0, |&max| (max as f64).log2().max(1.0));
+    // sorted so the same manifold always renders the same file
+    let mut visited = sim.visited.iter().copied().collect::<Vec<_>>();
+    visited.sort_by_key(|b| (b.row, b.col, b.direction.index()));
+    for beam in visited {
+        let mut style = Sstyle::new();
+        style.stroke = Some("orange".to_string());
+        style.stroke_width = Some(match flows {
+            Some(flows) => 1.0 + 5.0 * (flows[&beam] as f64).log2() / widest,
+            None => 1.5,
+        });
+        let from = centre(beam.row, beam.col);
+        for step in manifold.steps(beam) {
+            let (dx, dy) = match step {
+                Step::Beam(next) => {
+                    let to = centre(next.row, next.col);
+                    (to.0 - from.0, to.1 - from.1)
+                }
+                // half a cell takes the beam to the edge of the manifold
+                Step::Exit(exit) => {
+                    let (dx, dy) = offset(exit.direction);
+                    (dx / 2.0, dy / 2.0)
+                }
+            };
+            let segment = Shape::Line(Line::new((0.0, 0.0), (dx, dy)));
+            place(&mut svg, segment, from, style.clone());
+        }
+    }
+
+    for (row, tiles) in manifold.tiles.iter().enumerate() {
+        for (col, tile) in tiles.iter().enumerate() {
+            let (x, y) = centre(row, col);
+            let mut style = Sstyle::new();
+            let (shape, at) = match tile {
+                Tile::Empty => continue,
+                Tile::Splitter => {
+                    let hit = sim.splits.contains(&(row, col));
+                    style.fill = Some(if hit { "red" } else { "grey" }.to_string());
+                    let triangle = vec![(0.0, -4.0), (4.0, 4.0), (-4.0, 4.0)];
+                    (Shape::Polygon(Polygon::new(triangle)), (x, y))
+                }
+                Tile::Mirror | Tile::BackMirror => {
+                    style.stroke = Some("black".to_string());
+                    style.stroke_width = Some(1.5);
+                    let end = if *tile == Tile::Mirror { -4.0 } else { 4.0 };
+                    let line = Line::new((-4.0, -end), (4.0, end));
+                    (Shape::Line(line), (x, y))
+                }
+                Tile::Blocker => {
+                    style.fill = Some("black".to_string());
+                    (Shape::Rect(Rect::new(8.0, 8.0)), (x - 4.0, y - 4.0))
+                }
+                Tile::Left | Tile::Right => {
+                    style.fill = Some("black".to_string());
+                    let tip = if *tile == Tile::Right { 4.0 } else { -4.0 };
+                    let arrow = vec![(tip, 0.0), (-tip, -4.0), (-tip, 4.0)];
+                    (Shape::Polygon(Polygon::new(arrow)), (x, y))
+                }
+            };
+            place(&mut svg, shape, at, style);
+        }
+    }
+
+    svg.add_default_group(group);
+    std::fs::write(file, svg_out(svg))?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
 
     #[test]
     fn example() {
@@ -97,7 +1069,7 @@ mod tests {
             .split('\n')
             .map(String::from)
             .collect();
-        let result = teleport(lines);
+        let result = teleport(lines, Sides::Exit);
         assert_eq!(21, result);
     }
 
@@ -122,7 +1094,455 @@ mod tests {
             .split('\n')
             .map(String::from)
             .collect();
-        let result = teleport2(lines);
-        assert_eq!(40, result);
+        let result = teleport2(lines, Sides::Exit, Counting::U128);
+        assert_eq!(40, result.unwrap());
+    }
+
+    const EXAMPLE_INPUT: &str = r".......S.......
+...............
+.......^.......
+...............
+......^.^......
+...............
+.....^.^.^.....
+...............
+....^.^...^....
+...............
+...^.^...^.^...
+...............
+..^...^.....^..
+...............
+.^.^.^.^.^...^.
+...............";
+
+    fn parse_manifold(input: &str) -> Manifold {
+        let lines = input.split('\n').map(String::from).collect::<Vec<_>>();
+        Manifold::parse(&lines).unwrap()
+    }
+
+    #[test]
+    fn engine_example() {
+        let manifold = parse_manifold(EXAMPLE_INPUT);
+        assert_eq!(21, manifold.simulate().splits.len());
+        assert_eq!(40, manifold.timelines(Counting::U128).unwrap());
+    }
+
+    #[test]
+    fn engine_input() {
+        let file = BufReader::new(File::open("src/bin/day7.txt").unwrap());
+        let lines = file.lines().map(Result::unwrap).collect::<Vec<_>>();
+        let manifold = Manifold::parse(&lines).unwrap();
+        assert_eq!(
+            teleport2(lines, Sides::Exit, Counting::U128).unwrap(),
+            manifold.timelines(Counting::U128).unwrap()
+        );
+    }
+
+    #[test]
+    fn mirrors() {
+        let manifold = parse_manifold(
+            r".S.
+.\.
+.#.",
+        );
+        let sim = manifold.simulate();
+        assert_eq!(
+            HashSet::from([Beam {
+                row: 1,
+                col: 2,
+                direction: East
+            }]),
+            sim.exits
+        );
+        assert_eq!(1, manifold.timelines(Counting::U128).unwrap());
+
+        // up through the splitter from below, which splits it too
+        let manifold = parse_manifold(
+            r"...
+S^.
+\/.",
+        );
+        let sim = manifold.simulate();
+        assert_eq!(HashSet::from([(1, 1)]), sim.splits);
+        assert_eq!(2, manifold.timelines(Counting::U128).unwrap());
+    }
+
+    #[test]
+    fn blockers_and_deflectors() {
+        // sideways into a splitter stops the beam
+        let manifold = parse_manifold(
+            r"S..
+>.^",
+        );
+        assert!(manifold.simulate().splits.is_empty());
+        assert!(manifold.simulate().exits.is_empty());
+        assert_eq!(1, manifold.timelines(Counting::U128).unwrap());
+
+        let manifold = parse_manifold(
+            r"..S
+..<
+#..",
+        );
+        let sim = manifold.simulate();
+        assert_eq!(1, sim.exits.len());
+        assert_eq!(1, manifold.timelines(Counting::U128).unwrap());
+    }
+
+    #[test]
+    fn loops() {
+        let manifold = parse_manifold(
+            r"S.
+><",
+        );
+        // the simulation stops once the beam repeats itself
+        assert_eq!(4, manifold.simulate().visited.len());
+        assert!(manifold.timelines(Counting::U128).is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let lines = ["S.S".to_string()];
+        assert!(Manifold::parse(&lines).is_err());
+        let lines = ["..".to_string()];
+        assert!(Manifold::parse(&lines).is_err());
+        let lines = ["S?".to_string()];
+        assert!(Manifold::parse(&lines).is_err());
+    }
+
+    /// A Galton board: every beam hits a splitter on every row, so there are `2^rows` timelines.
+    fn galton(rows: usize) -> Vec<String> {
+        let width = 2 * rows + 3;
+        let centre = rows + 1;
+        let mut lines = vec![format!(
+            "{}S{}",
+            ".".repeat(centre),
+            ".".repeat(width - centre - 1)
+        )];
+        for k in 0..rows {
+            let mut row = vec![b'.'; width];
+            for j in 0..=k {
+                row[centre - k + 2 * j] = b'^';
+            }
+            lines.push(String::from_utf8(row).unwrap());
+        }
+        lines
+    }
+
+    #[test]
+    fn tall_manifolds() {
+        let lines = galton(70);
+        let manifold = Manifold::parse(&lines).unwrap();
+        assert_eq!(
+            1 << 70,
+            teleport2(lines.clone(), Sides::Exit, Counting::U128).unwrap()
+        );
+        assert_eq!(1 << 70, manifold.timelines(Counting::U128).unwrap());
+
+        let error = teleport2(lines.clone(), Sides::Exit, Counting::U64).unwrap_err();
+        assert_eq!("Timeline count overflowed u64", error.to_string());
+        assert!(manifold.timelines(Counting::U64).is_err());
+
+        let p = 1_000_000_007;
+        let expected = (1u128 << 70) % p;
+        assert_eq!(
+            expected,
+            teleport2(lines, Sides::Exit, Counting::Modulo(p)).unwrap()
+        );
+        assert_eq!(expected, manifold.timelines(Counting::Modulo(p)).unwrap());
+
+        let lines = galton(130);
+        let manifold = Manifold::parse(&lines).unwrap();
+        assert!(teleport2(lines.clone(), Sides::Exit, Counting::U128).is_err());
+        assert!(manifold.timelines(Counting::U128).is_err());
+
+        // modulo the largest prime below 2^128, 2^128 is 159 so 2^130 is 4 * 159
+        let p = u128::MAX - 158;
+        assert_eq!(
+            636,
+            teleport2(lines, Sides::Exit, Counting::Modulo(p)).unwrap()
+        );
+        assert_eq!(636, manifold.timelines(Counting::Modulo(p)).unwrap());
+    }
+
+    fn lines(input: &str) -> Vec<String> {
+        input.split('\n').map(String::from).collect()
+    }
+
+    /// Splits and timelines for each way of handling the sides.
+    fn both_sides(input: &str) -> [(u64, u128); 2] {
+        [Sides::Exit, Sides::Reflect].map(|sides| {
+            (
+                teleport(lines(input), sides),
+                teleport2(lines(input), sides, Counting::U128).unwrap(),
+            )
+        })
+    }
+
+    #[test]
+    fn left_edge() {
+        // the left half leaves, or bounces right to join the right half
+        let input = "S..\n^..";
+        assert_eq!([(1, 2), (1, 2)], both_sides(input));
+        assert_eq!(2, parse_manifold(input).timelines(Counting::U128).unwrap());
+    }
+
+    #[test]
+    fn right_edge() {
+        let input = "..S\n..^";
+        assert_eq!([(1, 2), (1, 2)], both_sides(input));
+        assert_eq!(2, parse_manifold(input).timelines(Counting::U128).unwrap());
+    }
+
+    #[test]
+    fn both_edges() {
+        // with nowhere to bounce to, both halves leave
+        let input = "S\n^";
+        assert_eq!([(1, 2), (1, 2)], both_sides(input));
+        assert_eq!(2, parse_manifold(input).timelines(Counting::U128).unwrap());
+    }
+
+    #[test]
+    fn reflected_beams_split_again() {
+        // exiting, one half leaves at each splitter; reflected, both halves reach the second
+        let input = "S.\n^.\n.^";
+        assert_eq!([(2, 3), (2, 4)], both_sides(input));
+        assert_eq!(3, parse_manifold(input).timelines(Counting::U128).unwrap());
+    }
+
+    #[test]
+    fn unreached_splitters() {
+        let input = "S..\n..^\n^..";
+        assert_eq!([(1, 2), (1, 2)], both_sides(input));
+        assert_eq!([(0, 1), (0, 1)], both_sides("S..\n..^"));
+    }
+
+    #[test]
+    fn flows() {
+        let manifold = parse_manifold(EXAMPLE_INPUT);
+        let flows = manifold.flows(Counting::U128).unwrap();
+        assert_eq!(1, flows[&manifold.start]);
+        // every timeline leaves through the bottom row
+        let bottom = manifold.tiles.len() - 1;
+        let leaving = flows
+            .iter()
+            .filter(|(beam, _)| beam.row == bottom)
+            .map(|(_, flow)| flow)
+            .sum::<u128>();
+        assert_eq!(40, leaving);
+    }
+
+    #[test]
+    fn svg() {
+        let manifold = parse_manifold("..S..\n..^..\n.....\n^....");
+        let sim = manifold.simulate();
+        let flows = manifold.flows(Counting::U128).unwrap();
+        let file = env::temp_dir().join(format!("day7_test_{}.svg", std::process::id()));
+        let file = file.to_str().unwrap();
+        to_svg(file, &manifold, &sim, Some(&flows)).unwrap();
+
+        let svg = std::fs::read_to_string(file).unwrap();
+        assert!(svg.contains("red"), "the splitter at row 1 is hit");
+        assert!(
+            svg.contains("grey"),
+            "the splitter at row 3 is never reached"
+        );
+        assert!(svg.contains("<line"));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn graph_example() {
+        let manifold = parse_manifold(EXAMPLE_INPUT);
+        let graph = manifold.graph().unwrap();
+        let splitters = graph
+            .nodes
+            .iter()
+            .filter(|n| matches!(n, Node::Splitter(_)))
+            .count();
+        assert_eq!(21, splitters);
+
+        let counting = Counting::U128;
+        let to = graph.paths_to(counting).unwrap();
+        let exits = (0..graph.nodes.len())
+            .filter(|&n| graph.outgoing[n].is_empty())
+            .map(|n| to[n])
+            .sum::<u128>();
+        assert_eq!(40, exits);
+        assert_eq!(40, graph.paths_from(counting).unwrap()[0]);
+
+        let first = graph
+            .find(Node::Splitter(Beam {
+                row: 2,
+                col: 7,
+                direction: South,
+            }))
+            .unwrap();
+        assert_eq!(40, graph.paths_through(first, counting).unwrap());
+        assert_eq!(Some((6, 11)), graph.busiest_exit(counting).unwrap());
+    }
+
+    #[test]
+    fn graph_export() {
+        // the left half leaves by the side and the right half runs into a blocker
+        let manifold = parse_manifold("S.\n^.\n.#\n..");
+        let graph = manifold.graph().unwrap();
+        assert_eq!(
+            "digraph manifold {\n  \
+               n0 [label=\"start 0,0 South\" shape=doublecircle];\n  \
+               n1 [label=\"splitter 1,0 South\" shape=triangle];\n  \
+               n2 [label=\"exit 1,0 West\" shape=box];\n  \
+               n3 [label=\"stopped 2,1 South\" shape=box];\n  \
+               n0 -> n1 [label=1];\n  \
+               n1 -> n2 [label=1];\n  \
+               n1 -> n3 [label=2];\n\
+             }\n",
+            graph.to_dot()
+        );
+        assert_eq!(
+            r#"{
+  "nodes": [
+    {"id": 0, "kind": "start", "row": 0, "col": 0, "direction": "South"},
+    {"id": 1, "kind": "splitter", "row": 1, "col": 0, "direction": "South"},
+    {"id": 2, "kind": "exit", "row": 1, "col": 0, "direction": "West"},
+    {"id": 3, "kind": "stopped", "row": 2, "col": 1, "direction": "South"}
+  ],
+  "edges": [
+    {"from": 0, "to": 1, "cells": 1},
+    {"from": 1, "to": 2, "cells": 1},
+    {"from": 1, "to": 3, "cells": 2}
+  ]
+}"#,
+            graph.to_json()
+        );
+        assert_eq!(Some((0, 1)), graph.busiest_exit(Counting::U128).unwrap());
+        assert!(parse_manifold("S.\n><").graph().is_err());
+    }
+
+    #[test]
+    fn multiply() {
+        assert_eq!(42, Counting::U64.mul(6, 7).unwrap());
+        assert!(Counting::U64.mul(1 << 32, 1 << 32).is_err());
+        assert!(Counting::U128.mul(1 << 64, 1 << 64).is_err());
+        let m = u128::MAX - 158;
+        // (m - 1)^2 = 1 mod m, which needs 256 bits done naively
+        assert_eq!(1, Counting::Modulo(m).mul(m - 1, m - 1).unwrap());
+        assert_eq!(0, Counting::Modulo(7).mul(3, 0).unwrap());
+    }
+
+    /// A random manifold of splitters, like the puzzle input but small enough to brute force.
+    /// As in the input, no two splitters are side by side.
+    fn random_manifold(rng: &mut StdRng) -> Vec<String> {
+        let width = rng.random_range(1..8);
+        let start = rng.random_range(0..width);
+        let mut lines = vec![
+            (0..width)
+                .map(|c| if c == start { 'S' } else { '.' })
+                .collect(),
+        ];
+        for _ in 0..rng.random_range(0..10) {
+            let mut line = String::new();
+            for _ in 0..width {
+                let splitter = !line.ends_with('^') && rng.random_bool(0.4);
+                line.push(if splitter { '^' } else { '.' });
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn enumerate_example() {
+        let graph = parse_manifold(EXAMPLE_INPUT).graph().unwrap();
+        let timelines = graph.timelines().collect::<Vec<_>>();
+        assert_eq!(40, timelines.len());
+        assert_eq!("LLLLLLL -> exit 15,0", timelines[0].to_string());
+        assert_eq!(
+            "RRRRRRR -> exit 15,14",
+            timelines.last().unwrap().to_string()
+        );
+        for pair in timelines.windows(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..300 {
+            let lines = random_manifold(&mut rng);
+            let graph = Manifold::parse(&lines).unwrap().graph().unwrap();
+            let timelines = graph.timelines().collect::<HashSet<_>>();
+            assert_eq!(
+                teleport2(lines.clone(), Sides::Exit, Counting::U128).unwrap(),
+                graph.timelines().count() as u128,
+                "{lines:?}"
+            );
+            assert_eq!(graph.timelines().count(), timelines.len(), "{lines:?}");
+        }
+    }
+
+    #[test]
+    fn engine_reflects() {
+        let mut rng = StdRng::seed_from_u64(40);
+        let mut bounced = 0;
+        for _ in 0..300 {
+            let lines = random_manifold(&mut rng);
+            let manifold = Manifold {
+                sides: Sides::Reflect,
+                ..Manifold::parse(&lines).unwrap()
+            };
+            let expected = teleport2(lines.clone(), Sides::Reflect, Counting::U128).unwrap();
+            if expected != teleport2(lines.clone(), Sides::Exit, Counting::U128).unwrap() {
+                bounced += 1;
+            }
+            assert_eq!(
+                expected,
+                manifold.timelines(Counting::U128).unwrap(),
+                "{lines:?}"
+            );
+            assert_eq!(
+                expected,
+                manifold.graph().unwrap().timelines().count() as u128,
+                "{lines:?}"
+            );
+            assert_eq!(
+                teleport(lines.clone(), Sides::Reflect),
+                manifold.simulate().splits.len() as u64,
+                "{lines:?}"
+            );
+        }
+        assert!(bounced > 0, "no beam ever reached a side");
+
+        // each splitter is against a side, so both halves go the one way: right into the second
+        // splitter, then back left
+        let lines = lines("S.\n^.\n.^");
+        let manifold = Manifold {
+            sides: Sides::Reflect,
+            ..Manifold::parse(&lines).unwrap()
+        };
+        assert_eq!(4, manifold.timelines(Counting::U128).unwrap());
+        let graph = manifold.graph().unwrap();
+        let timelines = graph.timelines().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(4, timelines.len());
+        assert!(
+            timelines.iter().all(|t| t.ends_with("exit 2,0")),
+            "{timelines:?}"
+        );
+    }
+
+    #[test]
+    fn sample_uniformly() {
+        let graph = parse_manifold(EXAMPLE_INPUT).graph().unwrap();
+        let sampler = Sampler::new(&graph).unwrap();
+        let mut rng = StdRng::seed_from_u64(43);
+        let mut seen = HashMap::new();
+        for _ in 0..40_000 {
+            *seen.entry(sampler.sample(&mut rng)).or_insert(0) += 1;
+        }
+        // every timeline, each about a thousand times
+        assert_eq!(40, seen.len());
+        for (timeline, n) in seen {
+            assert!((850..1150).contains(&n), "{timeline} drawn {n} times");
+        }
     }
 }
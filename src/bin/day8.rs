@@ -1,17 +1,81 @@
-use anyhow::Error;
+use anyhow::{Error, anyhow, bail};
 use derivative::Derivative;
-use std::collections::{HashMap, HashSet};
+use simple_svg::{Circle, Group, Line, Shape, Sstyle, Svg, Widget, svg_out};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::fs::File;
 use std::io::{BufRead, BufReader, stdin};
-use std::{env, mem};
+use std::ops::Range;
+use std::{env, iter, mem};
+
+/// Options followed by a value, which is not the input file.
+const VALUE_OPTIONS: [&str; 9] = [
+    "--nearest",
+    "--within",
+    "--connections",
+    "--export",
+    "--svg",
+    "--cut-clusters",
+    "--cut-distance",
+    "--dimensions",
+    "--metric",
+];
+
+struct Options {
+    /// list the `k` junction boxes nearest box `id`, given as `<id>,<k>`
+    nearest: Option<(usize, i64)>,
+    /// list the junction boxes within a distance of box `id`, given as `<id>,<distance>`
+    within: Option<(usize, i64)>,
+    /// how many of the closest pairs to connect before measuring the circuits
+    connections: usize,
+    /// print the links and each box's circuit as `csv`, `json` or `dot` instead of the answers
+    export: Option<String>,
+    /// plot the boxes and links, coloured by circuit, to this SVG file
+    svg: Option<String>,
+    /// print the single-linkage merge history as a tree
+    dendrogram: bool,
+    /// cut the merge history into this many clusters and print their sizes
+    cut_clusters: Option<i64>,
+    /// cut the merge history at this distance and print the cluster sizes
+    cut_distance: Option<i64>,
+    /// print how many circuits there are of each size
+    show_histogram: bool,
+    /// how distances between boxes are measured
+    metric: Metric,
+}
 
 fn main() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
-    let filename = if args.len() < 2 {
-        "src/bin/day8.txt"
-    } else {
-        &args[1]
+    let filename = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(i, a)| !a.starts_with("--") && !VALUE_OPTIONS.contains(&args[i - 1].as_str()))
+        .map(|(_, a)| a.as_str())
+        .unwrap_or("src/bin/day8.txt");
+    let value = |option: &str| {
+        args.iter()
+            .position(|a| a == option)
+            .map(|pos| args.get(pos + 1).cloned())
+    };
+    let options = Options {
+        nearest: query(&args, "--nearest")?,
+        within: query(&args, "--within")?,
+        connections: number(&args, "--connections")?.map_or(1000, |k| k as usize),
+        export: value("--export").map(|v| v.expect("Missing export format")),
+        svg: value("--svg").map(|v| v.expect("Missing SVG file")),
+        dendrogram: args.iter().any(|a| a == "--dendrogram"),
+        cut_clusters: number(&args, "--cut-clusters")?,
+        cut_distance: number(&args, "--cut-distance")?,
+        show_histogram: args.iter().any(|a| a == "--histogram"),
+        // `euclidean`, `manhattan` or `chebyshev`
+        metric: match value("--metric") {
+            Some(name) => Metric::parse(&name.unwrap_or_default())?,
+            None => Metric::Euclidean,
+        },
     };
+    // how many coordinates each junction box has
+    let dimensions = number(&args, "--dimensions")?.unwrap_or(3);
 
     let file: Box<dyn BufRead> = if filename == "-" {
         Box::new(stdin().lock())
@@ -22,210 +86,843 @@ fn main() -> Result<(), Error> {
     };
     let text = file.lines().map(Result::unwrap).collect::<Vec<_>>();
 
-    let result = junctions(text, 1_000_000)?;
+    match dimensions {
+        1 => run::<1>(text, &options),
+        2 => run::<2>(text, &options),
+        3 => run::<3>(text, &options),
+        4 => run::<4>(text, &options),
+        _ => bail!("--dimensions must be between 1 and 4"),
+    }
+}
+
+fn run<const N: usize>(text: Vec<String>, options: &Options) -> anyhow::Result<()> {
+    let points = parse_lines::<N>(text)?;
+    let metric = options.metric;
+
+    if options.nearest.is_some() || options.within.is_some() {
+        let tree = KdTree::new(&points, metric);
+        let found = match (options.nearest, options.within) {
+            (Some((id, k)), _) => tree.nearest(junction_box(&points, id, "--nearest")?, k as usize),
+            (_, Some((id, length))) => tree.within(
+                junction_box(&points, id, "--within")?,
+                metric.distance_for(length),
+            ),
+            _ => unreachable!(),
+        };
+        for (distance, id) in found {
+            let coords = points[id].coords.map(|c| c.to_string()).join(",");
+            println!("{id}: {coords} at {:.1}", metric.length(distance));
+        }
+        return Ok(());
+    }
+
+    if options.dendrogram || options.cut_clusters.is_some() || options.cut_distance.is_some() {
+        let tree = Dendrogram::new(&points, metric);
+        if options.dendrogram {
+            print!("{}", tree.to_ascii());
+        }
+        let cut = match (options.cut_clusters, options.cut_distance) {
+            (Some(clusters), _) => tree.cut_clusters(clusters as usize),
+            (_, Some(length)) => tree.cut_distance(metric.distance_for(length)),
+            _ => return Ok(()),
+        };
+        for (size, count) in histogram(&cut) {
+            println!("{count} of size {size}");
+        }
+        return Ok(());
+    }
+
+    let result = connect(&points, options.connections, metric);
 
-    println!("{result}");
+    if let Some(svg) = &options.svg {
+        result.to_svg(svg, &points)?;
+    }
+    if let Some(format) = &options.export {
+        match format.as_str() {
+            "csv" => print!("{}", result.to_csv(&points)),
+            "json" => println!("{}", result.to_json(&points)),
+            "dot" => print!("{}", result.to_dot(&points)),
+            _ => bail!("Unknown export format {format:?}, expected csv, json or dot"),
+        }
+        return Ok(());
+    }
+
+    if options.show_histogram {
+        for (size, count) in &result.histogram {
+            println!("{count} of size {size}");
+        }
+    }
+    println!("largest circuits: {}", result.largest_product(3));
+    match result.unifying_product() {
+        Some(product) => println!("last link: {product}"),
+        None => println!("last link: never"),
+    }
 
     Ok(())
 }
 
+/// Parses the non-negative number following `option`, if present.
+fn number(args: &[String], option: &str) -> anyhow::Result<Option<i64>> {
+    let Some(pos) = args.iter().position(|a| a == option) else {
+        return Ok(None);
+    };
+    match args.get(pos + 1).map(|n| n.parse()) {
+        Some(Ok(n)) if n >= 0 => Ok(Some(n)),
+        _ => bail!("{option} needs a number"),
+    }
+}
+
+/// Parses the `<id>,<n>` value of a query option, if present.
+fn query(args: &[String], option: &str) -> anyhow::Result<Option<(usize, i64)>> {
+    let Some(pos) = args.iter().position(|a| a == option) else {
+        return Ok(None);
+    };
+    let value = args.get(pos + 1).and_then(|v| v.split_once(','));
+    match value.map(|(id, n)| (id.parse(), n.parse())) {
+        Some((Ok(id), Ok(n))) if n >= 0 => Ok(Some((id, n))),
+        _ => bail!("{option} needs a junction box id and a number, as <id>,<n>"),
+    }
+}
+
+/// The junction box a query option asks about, if there is one with that id.
+fn junction_box<'a, const N: usize>(
+    points: &'a [Point<N>],
+    id: usize,
+    option: &str,
+) -> anyhow::Result<&'a Point<N>> {
+    points.get(id).ok_or_else(|| {
+        anyhow!(
+            "{option} needs a junction box id below {}, not {id}",
+            points.len()
+        )
+    })
+}
+
 #[derive(Derivative, Clone, Debug)]
 #[derivative(Hash, PartialEq, Eq)]
-struct Point {
-    x: i64,
-    y: i64,
-    z: i64,
+struct Point<const N: usize = 3> {
+    coords: [i64; N],
     #[derivative(Hash = "ignore")]
     #[derivative(PartialEq = "ignore")]
     id: usize,
 }
 
-fn junctions(lines: Vec<String>, connections: usize) -> anyhow::Result<usize> {
-    let points = parse_lines(lines);
-    let mut circuits = (0..points.len())
-        .map(|i| HashSet::from([i]))
-        .collect::<Vec<_>>();
-    let mut point_to_circuit = (0..points.len()).collect::<Vec<usize>>();
-    let mut junctions: HashSet<(usize, usize)> = HashSet::new();
-
-    for _ in 0..connections {
-        let (left_point_id, right_point_id) = closest_neighbours(&points, &circuits, &junctions);
-
-        junctions.insert((left_point_id, right_point_id));
-        junctions.insert((right_point_id, left_point_id));
-
-        let left_circuit_id = point_to_circuit[left_point_id];
-        let right_circuit_id = point_to_circuit[right_point_id];
-        if left_circuit_id != right_circuit_id {
-            let mut obsolete_circuit_point_ids = mem::take(&mut circuits[right_circuit_id]);
-            for point_id in obsolete_circuit_point_ids.iter() {
-                point_to_circuit[*point_id] = left_circuit_id;
+/// Coordinates are kept below this in magnitude, so squared distances over up to four axes fit
+/// in a `Distance`.
+const MAX_COORDINATE: i64 = 1 << 61;
+
+/// A distance between two points, wide enough to hold squared offsets without overflowing.
+type Distance = i128;
+
+/// How far apart two points are. Distances are kept as exact integers, so Euclidean ones are
+/// squared; only their order matters to the clustering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Metric {
+    Euclidean,
+    Manhattan,
+    Chebyshev,
+}
+
+impl Metric {
+    fn parse(name: &str) -> anyhow::Result<Metric> {
+        match name {
+            "euclidean" => Ok(Metric::Euclidean),
+            "manhattan" => Ok(Metric::Manhattan),
+            "chebyshev" => Ok(Metric::Chebyshev),
+            _ => bail!("Unknown metric {name:?}, expected euclidean, manhattan or chebyshev"),
+        }
+    }
+
+    #[inline]
+    fn distance<const N: usize>(self, a: &Point<N>, b: &Point<N>) -> Distance {
+        let offsets =
+            iter::zip(&a.coords, &b.coords).map(|(&p, &q)| (p as Distance - q as Distance).abs());
+        match self {
+            Metric::Euclidean => offsets.map(|d| d * d).sum(),
+            Metric::Manhattan => offsets.sum(),
+            Metric::Chebyshev => offsets.max().unwrap_or(0),
+        }
+    }
+
+    /// The least distance between two points `offset` apart along one axis.
+    #[inline]
+    fn axis_distance(self, offset: Distance) -> Distance {
+        match self {
+            Metric::Euclidean => offset * offset,
+            Metric::Manhattan | Metric::Chebyshev => offset.abs(),
+        }
+    }
+
+    /// The distance of a length in the input's units.
+    fn distance_for(self, length: i64) -> Distance {
+        let length = length as Distance;
+        match self {
+            Metric::Euclidean => length * length,
+            Metric::Manhattan | Metric::Chebyshev => length,
+        }
+    }
+
+    /// The length in the input's units of a distance.
+    fn length(self, distance: Distance) -> f64 {
+        match self {
+            Metric::Euclidean => (distance as f64).sqrt(),
+            Metric::Manhattan | Metric::Chebyshev => distance as f64,
+        }
+    }
+}
+
+/// Name of a coordinate in the exports: `x`, `y`, `z` and `w`, then `x4` and so on.
+fn axis_name(axis: usize) -> String {
+    match ["x", "y", "z", "w"].get(axis) {
+        Some(name) => name.to_string(),
+        None => format!("x{axis}"),
+    }
+}
+
+/// What connecting the closest pairs of junction boxes led to, from a single run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Circuits<const N: usize = 3> {
+    /// how many circuits there are of each size after the first `connections` pairs
+    histogram: BTreeMap<usize, usize>,
+    /// the circuit each junction box is in after the first `connections` pairs, numbered in
+    /// order of their lowest box id
+    circuit_ids: Vec<usize>,
+    /// the pairs among the first `connections` that joined two circuits, as
+    /// `(distance, id, id)`, making a minimum spanning forest of the circuits
+    links: Vec<(Distance, usize, usize)>,
+    /// the pair whose connection put every junction box in one circuit, if that happened
+    unifying_pair: Option<(Point<N>, Point<N>)>,
+}
+
+impl<const N: usize> Circuits<N> {
+    /// Product of the sizes of the `n` largest circuits.
+    fn largest_product(&self, n: usize) -> usize {
+        self.histogram
+            .iter()
+            .rev()
+            .flat_map(|(&size, &count)| iter::repeat_n(size, count))
+            .take(n)
+            .product()
+    }
+
+    /// Product of the first coordinates of the unifying pair, widened so it can't overflow.
+    fn unifying_product(&self) -> Option<i128> {
+        self.unifying_pair
+            .as_ref()
+            .map(|(a, b)| a.coords[0] as i128 * b.coords[0] as i128)
+    }
+}
+
+/// Graphviz's `dark28` colour scheme, used for circuits with more than one box.
+const PALETTE: [&str; 8] = [
+    "#1b9e77", "#d95f02", "#7570b3", "#e7298a", "#66a61e", "#e6ab02", "#a6761d", "#666666",
+];
+
+/// Side of the square SVG projection.
+const PLOT_SIZE: f64 = 800.0;
+
+impl<const N: usize> Circuits<N> {
+    /// Number of boxes in each circuit, by circuit id.
+    fn circuit_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![];
+        for &circuit in &self.circuit_ids {
+            if circuit >= sizes.len() {
+                sizes.resize(circuit + 1, 0);
             }
-            circuits[left_circuit_id].extend(obsolete_circuit_point_ids.drain());
+            sizes[circuit] += 1;
+        }
+        sizes
+    }
+
+    /// Colour of each circuit by id, handed out from the largest circuit down so the biggest
+    /// ones get different colours; `None` for circuits of a single box.
+    fn colours(&self) -> Vec<Option<&'static str>> {
+        let sizes = self.circuit_sizes();
+        let mut ranked = (0..sizes.len())
+            .filter(|&c| sizes[c] > 1)
+            .collect::<Vec<_>>();
+        ranked.sort_by_key(|&c| (Reverse(sizes[c]), c));
+        let mut colours = vec![None; sizes.len()];
+        for (rank, circuit) in ranked.into_iter().enumerate() {
+            colours[circuit] = Some(PALETTE[rank % PALETTE.len()]);
+        }
+        colours
+    }
+
+    /// A table of boxes, then a blank line and a table of links.
+    fn to_csv(&self, points: &[Point<N>]) -> String {
+        let axes = (0..N).map(axis_name).collect::<Vec<_>>();
+        let mut csv = format!("id,{},circuit\n", axes.join(","));
+        for (point, circuit) in points.iter().zip(&self.circuit_ids) {
+            let coords = point.coords.map(|c| c.to_string()).join(",");
+            csv += &format!("{},{coords},{circuit}\n", point.id);
+        }
+        csv += "\nfrom,to,distance\n";
+        for (distance, from, to) in &self.links {
+            csv += &format!("{from},{to},{distance}\n");
+        }
+        csv
+    }
+
+    fn to_json(&self, points: &[Point<N>]) -> String {
+        let boxes = points
+            .iter()
+            .zip(&self.circuit_ids)
+            .map(|(point, circuit)| {
+                let coords = (0..N)
+                    .map(|axis| format!(r#""{}": {}"#, axis_name(axis), point.coords[axis]))
+                    .collect::<Vec<_>>();
+                format!(
+                    r#"{{"id": {}, {}, "circuit": {circuit}}}"#,
+                    point.id,
+                    coords.join(", ")
+                )
+            })
+            .collect::<Vec<_>>();
+        let links = self
+            .links
+            .iter()
+            .map(|(distance, from, to)| {
+                format!(r#"{{"from": {from}, "to": {to}, "distance": {distance}}}"#)
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"boxes\": [\n    {}\n  ],\n  \"links\": [\n    {}\n  ]\n}}",
+            boxes.join(",\n    "),
+            links.join(",\n    ")
+        )
+    }
+
+    /// An undirected graph of the links, with boxes filled in their circuit's colour.
+    fn to_dot(&self, points: &[Point<N>]) -> String {
+        let colours = self.colours();
+        let mut dot = String::from("graph circuits {\n");
+        for (point, &circuit) in points.iter().zip(&self.circuit_ids) {
+            let id = point.id;
+            let coords = point.coords.map(|c| c.to_string()).join(",");
+            let colour = colours[circuit].unwrap_or("white");
+            dot += &format!(
+                "  n{id} [label=\"{id}\\n{coords}\" style=filled fillcolor=\"{colour}\"];\n"
+            );
+        }
+        for (distance, from, to) in &self.links {
+            dot += &format!("  n{from} -- n{to} [label={distance}];\n");
+        }
+        dot += "}\n";
+        dot
+    }
 
-            // are all the points now in one circuit?
-            if circuits[left_circuit_id].len() == points.len() {
-                let result = points[left_point_id].x as usize * points[right_point_id].x as usize;
-                println!("*** {result} !!!");
-                return Ok(result);
+    /// Draws the boxes and links, with each circuit of more than one box in its own colour.
+    /// Boxes with three or more coordinates are drawn in an isometric projection of the first
+    /// three, and others flat.
+    fn to_svg(&self, file: &str, points: &[Point<N>]) -> anyhow::Result<()> {
+        let cos = 30f64.to_radians().cos();
+        let project = |p: &Point<N>| {
+            let c = |axis: usize| p.coords.get(axis).map_or(0.0, |&c| c as f64);
+            let (x, y, z) = (c(0), c(1), c(2));
+            if N < 3 {
+                (x, -y)
+            } else {
+                ((x - z) * cos, (x + z) / 2.0 - y)
             }
         };
+        let projected = points.iter().map(project).collect::<Vec<_>>();
+
+        // fit the projection into the plot, keeping its aspect ratio
+        let (mut min, mut max) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        for &(u, v) in &projected {
+            min = (min.0.min(u), min.1.min(v));
+            max = (max.0.max(u), max.1.max(v));
+        }
+        let margin = 10.0;
+        let span = (max.0 - min.0).max(max.1 - min.1).max(1.0);
+        let scale = (PLOT_SIZE - 2.0 * margin) / span;
+        let plot =
+            |(u, v): (f64, f64)| (margin + (u - min.0) * scale, margin + (v - min.1) * scale);
+
+        let mut svg = Svg::new(PLOT_SIZE, PLOT_SIZE);
+        let mut group = Group::new();
+
+        let mut link_style = Sstyle::new();
+        link_style.stroke = Some("black".to_string());
+        link_style.stroke_width = Some(1.0);
+        for &(_, from, to) in &self.links {
+            let (x1, y1) = plot(projected[from]);
+            let (x2, y2) = plot(projected[to]);
+            let line = svg.add_shape(Shape::Line(Line::new((0.0, 0.0), (x2 - x1, y2 - y1))));
+            group.place_widget(Widget {
+                shape_id: line,
+                at: Some((x1, y1)),
+                style: Some(link_style.clone()),
+                ..Default::default()
+            });
+        }
+
+        let colours = self.colours();
+        for (&position, &circuit) in projected.iter().zip(&self.circuit_ids) {
+            let mut style = Sstyle::new();
+            let radius = match colours[circuit] {
+                None => {
+                    style.fill = Some("lightgrey".to_string());
+                    2.0
+                }
+                Some(colour) => {
+                    style.fill = Some(colour.to_string());
+                    4.0
+                }
+            };
+            group.place_widget(Widget {
+                shape_id: svg.add_shape(Shape::Circle(Circle::new(radius))),
+                at: Some(plot(position)),
+                style: Some(style),
+                ..Default::default()
+            });
+        }
+
+        svg.add_default_group(group);
+        std::fs::write(file, svg_out(svg))?;
+        Ok(())
     }
-    // find 3 largest circuits
-    let mut circuit_sizes = circuits.iter().map(HashSet::len).collect::<Vec<_>>();
-    circuit_sizes.sort();
-    println!("{circuit_sizes:?}");
-    Ok(circuit_sizes.into_iter().rev().take(3).product())
 }
 
-fn parse_lines(lines: Vec<String>) -> Vec<Point> {
-    lines
-        .iter()
-        .enumerate()
-        .map(|(id, l)| {
-            let mut parts = l.split(',').map(|d| d.parse::<i64>().unwrap());
-            Point {
-                x: parts.next().unwrap(),
-                y: parts.next().unwrap(),
-                z: parts.next().unwrap(),
-                id,
+fn connect<const N: usize>(points: &[Point<N>], connections: usize, metric: Metric) -> Circuits<N> {
+    let mut circuits = DisjointSet::new(points.len());
+    let mut snapshot = (connections == 0).then(|| circuits.labels());
+    let mut links = vec![];
+    let mut unifying_pair = None;
+
+    // Kruskal: join the closest pairs first, counting pairs already in the same circuit too
+    for (taken, edge) in candidate_edges(points, metric).enumerate() {
+        let (_, left_point_id, right_point_id) = edge;
+        if circuits.union(left_point_id, right_point_id) {
+            if taken < connections {
+                links.push(edge);
             }
-        })
-        .collect()
+            if circuits.sets() == 1 {
+                unifying_pair = Some((
+                    points[left_point_id].clone(),
+                    points[right_point_id].clone(),
+                ));
+            }
+        }
+        if taken + 1 == connections {
+            snapshot = Some(circuits.labels());
+        }
+        // are all the points now in one circuit? nothing changes after that
+        if circuits.sets() == 1 {
+            break;
+        }
+    }
+
+    let circuit_ids = snapshot.unwrap_or_else(|| circuits.labels());
+    Circuits {
+        histogram: histogram(&circuit_ids),
+        circuit_ids,
+        links,
+        unifying_pair,
+    }
 }
 
-fn closest_neighbours(
-    points: &[Point],
-    circuits: &[HashSet<usize>],
-    junctions: &HashSet<(usize, usize)>,
-) -> (usize, usize) {
-    let midpoints: Vec<_> = circuits
-        .iter()
-        .filter(|circuit| !circuit.is_empty())
-        .map(|point_ids| midpoint(points, point_ids))
-        .collect();
-    let num_midpoints = midpoints.len();
+/// One step of single-linkage clustering: two clusters joined by their closest pair of boxes.
+///
+/// Clusters are numbered as in SciPy: box `i` starts as cluster `i`, and the `j`th merge makes
+/// cluster `n + j` for `n` boxes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Merge {
+    left: usize,
+    right: usize,
+    /// distance between the closest boxes of the two clusters
+    distance: Distance,
+    /// number of boxes in the merged cluster
+    size: usize,
+}
 
-    let midpoint_min_distance2_estimate = midpoints
-        .iter()
-        .enumerate()
-        .map(|(i, p)| (p, &midpoints[(i + 1) % num_midpoints]))
-        .min_by_key(tuple_weight)
-        .as_ref()
-        .map(tuple_weight)
-        .unwrap_or(1);
-    let midpoint_min_distance_estimate =
-        (midpoint_min_distance2_estimate as f64).sqrt().round() as i64;
-    println!("midpoint distance estimate: {midpoint_min_distance_estimate}");
-
-    let mut blocks: HashMap<(i64, i64, i64), Vec<&Point>> = HashMap::new();
-
-    for point in points {
-        let xb = point.x / midpoint_min_distance_estimate;
-        let yb = point.y / midpoint_min_distance_estimate;
-        let zb = point.z / midpoint_min_distance_estimate;
-        blocks.entry((xb, yb, zb)).or_default().push(point);
-    }
-
-    // for all blocks with at least 2 points, compute min pair
-    let closest_in_block = blocks
-        .iter()
-        .filter(|(_, points)| points.len() >= 2)
-        .filter_map(|(_, points)| {
-            points
-                .iter()
-                .enumerate()
-                .flat_map(|(i, p)| {
-                    points
-                        .iter()
-                        .skip(i + 1)
-                        .filter(|q| !junctions.contains(&(p.id, q.id)))
-                        // .filter(|q| point_to_circuit[p.id] != point_to_circuit[q.id])
-                        .map(move |q| (*p, *q))
-                })
-                .min_by_key(tuple_weight)
-        });
+/// The full merge history of the boxes, closest merges first, which can be cut at any point
+/// without connecting the boxes again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Dendrogram {
+    leaves: usize,
+    metric: Metric,
+    merges: Vec<Merge>,
+}
 
-    let neighbours: Vec<_> = (0..=1)
-        .flat_map(|dx| {
-            (-1..=1).flat_map(move |dy| {
-                (-1..=1)
-                    .filter(move |dz| dx != 0 || dy != 0 || *dz != 0)
-                    .map(move |dz| (dx, dy, dz))
-            })
-        })
-        .collect();
+impl Dendrogram {
+    fn new<const N: usize>(points: &[Point<N>], metric: Metric) -> Dendrogram {
+        let mut sets = DisjointSet::new(points.len());
+        // the cluster number of each set, by its root
+        let mut cluster = (0..points.len()).collect::<Vec<_>>();
+        let mut merges = vec![];
+        for (distance, a, b) in candidate_edges(points, metric) {
+            let (a, b) = (sets.find(a), sets.find(b));
+            if !sets.union(a, b) {
+                continue;
+            }
+            let root = sets.find(a);
+            merges.push(Merge {
+                left: cluster[a],
+                right: cluster[b],
+                distance,
+                size: sets.size[root],
+            });
+            cluster[root] = points.len() + merges.len() - 1;
+            if sets.sets() == 1 {
+                break;
+            }
+        }
+        Dendrogram {
+            leaves: points.len(),
+            metric,
+            merges,
+        }
+    }
 
-    let result = blocks
-        .iter()
-        .flat_map(|((bx, by, bz), points)| {
-            neighbours
-                .iter()
-                .map(move |offset| (bx + offset.0, by + offset.1, bz + offset.2))
-                .filter_map(|c| blocks.get(&c))
-                .flat_map(|others| {
-                    points.iter().flat_map(|p| {
-                        others
-                            .iter()
-                            .filter(|q| !junctions.contains(&(p.id, q.id)))
-                            .map(move |q| (*p, *q))
-                    })
-                })
-                .min_by_key(tuple_weight)
-        })
-        .chain(closest_in_block)
-        .min_by_key(tuple_weight)
-        .expect("No neighbours!");
+    /// The cluster of each box after the first `merges` merges, numbered in order of their
+    /// lowest box id.
+    fn labels_after(&self, merges: usize) -> Vec<usize> {
+        let mut sets = DisjointSet::new(self.leaves);
+        // any one box of each cluster
+        let mut member = (0..self.leaves).collect::<Vec<_>>();
+        for merge in &self.merges[..merges.min(self.merges.len())] {
+            sets.union(member[merge.left], member[merge.right]);
+            member.push(member[merge.left]);
+        }
+        sets.labels()
+    }
 
-    println!(
-        "joining {result:?}, points {} & {}",
-        result.0.id, result.1.id,
-    );
+    /// Cuts the tree into `clusters` clusters, or as few as there are if it never gets that far.
+    fn cut_clusters(&self, clusters: usize) -> Vec<usize> {
+        self.labels_after(self.leaves.saturating_sub(clusters))
+    }
 
-    (result.0.id, result.1.id)
+    /// Cuts the tree where it joins boxes further apart than `distance`.
+    fn cut_distance(&self, distance: Distance) -> Vec<usize> {
+        let merges = self.merges.partition_point(|m| m.distance <= distance);
+        self.labels_after(merges)
+    }
+
+    /// Draws the tree sideways, each cluster followed by its two halves indented below it.
+    fn to_ascii(&self) -> String {
+        let mut out = String::new();
+        // the roots of whatever never merged, which is just the last cluster once all have
+        let mut merged = vec![false; self.leaves + self.merges.len()];
+        for merge in &self.merges {
+            merged[merge.left] = true;
+            merged[merge.right] = true;
+        }
+        let mut pending = (0..merged.len())
+            .rev()
+            .filter(|&c| !merged[c])
+            .map(|c| (c, String::new(), String::new()))
+            .collect::<Vec<_>>();
+        while let Some((cluster, lead, indent)) = pending.pop() {
+            out += &lead;
+            if cluster < self.leaves {
+                out += &format!("box {cluster}\n");
+                continue;
+            }
+            let merge = self.merges[cluster - self.leaves];
+            out += &format!(
+                "{} boxes at {:.1}\n",
+                merge.size,
+                self.metric.length(merge.distance)
+            );
+            pending.push((merge.right, format!("{indent}└─ "), format!("{indent}   ")));
+            pending.push((merge.left, format!("{indent}├─ "), format!("{indent}│  ")));
+        }
+        out
+    }
 }
 
-fn midpoint<'a, I>(points: &[Point], point_ids: I) -> Point
-where
-    I: IntoIterator<Item = &'a usize>,
-{
-    if points.len() == 1 {
-        points[0].clone()
-    } else {
-        let mut count = 0;
-        let mut mean = Point {
-            x: 0,
-            y: 0,
-            z: 0,
-            id: 0,
+/// How many clusters there are of each size, given each box's cluster.
+fn histogram(labels: &[usize]) -> BTreeMap<usize, usize> {
+    let mut sizes = HashMap::<usize, usize>::new();
+    for &label in labels {
+        *sizes.entry(label).or_default() += 1;
+    }
+    let mut histogram = BTreeMap::new();
+    for size in sizes.into_values() {
+        *histogram.entry(size).or_insert(0) += 1;
+    }
+    histogram
+}
+
+/// Every pair of points as `(distance, id, id)` with the lower id first, closest first.
+///
+/// Pairs at the same distance come in order of their lower id and then their higher id, so
+/// equidistant pairs are always taken in the same order whatever the layout of the k-d tree.
+///
+/// Pairs are produced lazily: each point walks its neighbours outwards through a k-d tree, and a
+/// heap merges those walks, so only the pairs actually taken are ever looked at.
+fn candidate_edges<const N: usize>(points: &[Point<N>], metric: Metric) -> CandidateEdges<'_, N> {
+    let tree = KdTree::new(points, metric);
+    let mut edges = CandidateEdges {
+        neighbours: vec![vec![]; points.len()],
+        next: vec![0; points.len()],
+        heap: BinaryHeap::new(),
+        tree,
+    };
+    for id in 0..points.len() {
+        edges.fetch(id, 8);
+        edges.push_next(id);
+    }
+    edges
+}
+
+struct CandidateEdges<'a, const N: usize> {
+    tree: KdTree<'a, N>,
+    /// the nearest neighbours of each point found so far, closest first
+    neighbours: Vec<Vec<(Distance, usize)>>,
+    /// index into `neighbours` of the next pair to offer for each point
+    next: Vec<usize>,
+    /// the next pair from each point, as `(distance, id, neighbour id)`
+    heap: BinaryHeap<Reverse<(Distance, usize, usize)>>,
+}
+
+impl<const N: usize> CandidateEdges<'_, N> {
+    /// Looks up the `k` nearest neighbours of point `id`, leaving out the point itself.
+    fn fetch(&mut self, id: usize, k: usize) {
+        let point = &self.tree.points[id];
+        let mut found = self.tree.nearest(point, k + 1);
+        found.retain(|&(_, other)| other != id);
+        found.truncate(k);
+        self.neighbours[id] = found;
+    }
+
+    /// Offers the next neighbour of point `id`, fetching twice as many if they have run out.
+    fn push_next(&mut self, id: usize) {
+        let fetched = self.neighbours[id].len();
+        if self.next[id] == fetched && fetched + 1 < self.tree.points.len() {
+            self.fetch(id, (fetched * 2).max(1));
+        }
+        if let Some(&(distance, other)) = self.neighbours[id].get(self.next[id]) {
+            self.heap.push(Reverse((distance, id, other)));
+            self.next[id] += 1;
+        }
+    }
+}
+
+impl<const N: usize> Iterator for CandidateEdges<'_, N> {
+    type Item = (Distance, usize, usize);
+
+    fn next(&mut self) -> Option<(Distance, usize, usize)> {
+        loop {
+            let Reverse((distance, id, other)) = self.heap.pop()?;
+            self.push_next(id);
+            // each pair turns up from both ends, so keep the one from the lower id; as every
+            // neighbour list is sorted by `(distance, id)`, the heap pops ties in id order
+            if id < other {
+                return Some((distance, id, other));
+            }
+        }
+    }
+}
+
+/// A k-d tree over point ids, stored implicitly: each slice of `ids` has its splitting point in
+/// the middle, points before it on the low side of the axis and points after on the high side.
+struct KdTree<'a, const N: usize> {
+    points: &'a [Point<N>],
+    metric: Metric,
+    ids: Vec<usize>,
+}
+
+impl<'a, const N: usize> KdTree<'a, N> {
+    /// Expects every point's id to be its index in `points`.
+    fn new(points: &'a [Point<N>], metric: Metric) -> KdTree<'a, N> {
+        let mut ids = (0..points.len()).collect::<Vec<_>>();
+        KdTree::build(points, &mut ids, 0);
+        KdTree {
+            points,
+            metric,
+            ids,
+        }
+    }
+
+    fn build(points: &[Point<N>], ids: &mut [usize], axis: usize) {
+        if ids.len() <= 1 {
+            return;
+        }
+        let mid = ids.len() / 2;
+        ids.select_nth_unstable_by_key(mid, |&id| points[id].coords[axis]);
+        let (low, high) = ids.split_at_mut(mid);
+        KdTree::build(points, low, (axis + 1) % N);
+        KdTree::build(points, &mut high[1..], (axis + 1) % N);
+    }
+
+    /// The `k` points closest to `target` as `(distance, id)`, closest first and then by id.
+    /// Includes `target` itself if it is in the tree.
+    fn nearest(&self, target: &Point<N>, k: usize) -> Vec<(Distance, usize)> {
+        let mut best = BinaryHeap::with_capacity(k + 1);
+        if k > 0 {
+            self.search_nearest(0..self.ids.len(), 0, target, k, &mut best);
+        }
+        best.into_sorted_vec()
+    }
+
+    fn search_nearest(
+        &self,
+        range: Range<usize>,
+        axis: usize,
+        target: &Point<N>,
+        k: usize,
+        best: &mut BinaryHeap<(Distance, usize)>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let mid = (range.start + range.end) / 2;
+        let id = self.ids[mid];
+        let point = &self.points[id];
+
+        let candidate = (self.metric.distance(target, point), id);
+        if best.len() < k {
+            best.push(candidate);
+        } else if best.peek().is_some_and(|&worst| candidate < worst) {
+            best.pop();
+            best.push(candidate);
+        }
+
+        let next = (axis + 1) % N;
+        let offset = target.coords[axis] as Distance - point.coords[axis] as Distance;
+        let bound = self.metric.axis_distance(offset);
+        let (near, far) = if offset < 0 {
+            (range.start..mid, mid + 1..range.end)
+        } else {
+            (mid + 1..range.end, range.start..mid)
         };
-        for point_id in point_ids {
-            let point = &points[*point_id];
-            count += 1;
-            mean.x += point.x;
-            mean.y += point.y;
-            mean.z += point.z
-        }
-        mean.x /= count;
-        mean.y /= count;
-        mean.z /= count;
-        mean
+        self.search_nearest(near, next, target, k, best);
+        // points exactly as far as the worst kept one may still win on id
+        if best.len() < k || best.peek().is_some_and(|&(worst, _)| bound <= worst) {
+            self.search_nearest(far, next, target, k, best);
+        }
+    }
+
+    /// Every point at most `radius` from `target`, as `(distance, id)`, closest first and then
+    /// by id.
+    fn within(&self, target: &Point<N>, radius: Distance) -> Vec<(Distance, usize)> {
+        let mut found = vec![];
+        self.search_within(0..self.ids.len(), 0, target, radius, &mut found);
+        found.sort();
+        found
+    }
+
+    fn search_within(
+        &self,
+        range: Range<usize>,
+        axis: usize,
+        target: &Point<N>,
+        radius: Distance,
+        found: &mut Vec<(Distance, usize)>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let mid = (range.start + range.end) / 2;
+        let id = self.ids[mid];
+        let point = &self.points[id];
+
+        let distance = self.metric.distance(target, point);
+        if distance <= radius {
+            found.push((distance, id));
+        }
+
+        let next = (axis + 1) % N;
+        let offset = target.coords[axis] as Distance - point.coords[axis] as Distance;
+        let bound = self.metric.axis_distance(offset);
+        if offset <= 0 || bound <= radius {
+            self.search_within(range.start..mid, next, target, radius, found);
+        }
+        if offset >= 0 || bound <= radius {
+            self.search_within(mid + 1..range.end, next, target, radius, found);
+        }
     }
 }
 
-#[inline]
-fn tuple_weight((a, b): &(&Point, &Point)) -> i64 {
-    weight(a, b)
+/// Union-find over point ids, with path compression and union by size.
+struct DisjointSet {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+    sets: usize,
 }
 
-#[inline]
-fn weight(a: &Point, b: &Point) -> i64 {
-    (a.x - b.x).pow(2) + (a.y - b.y).pow(2) + (a.z - b.z).pow(2)
+impl DisjointSet {
+    fn new(n: usize) -> DisjointSet {
+        DisjointSet {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+            sets: n,
+        }
+    }
+
+    fn find(&mut self, x: usize) -> usize {
+        let mut root = x;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut x = x;
+        while self.parent[x] != root {
+            x = mem::replace(&mut self.parent[x], root);
+        }
+        root
+    }
+
+    /// Joins the sets containing `a` and `b`, returning false if they were already one set.
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        if self.size[a] < self.size[b] {
+            mem::swap(&mut a, &mut b);
+        }
+        self.parent[b] = a;
+        self.size[a] += self.size[b];
+        self.sets -= 1;
+        true
+    }
+
+    /// Number of disjoint sets.
+    fn sets(&self) -> usize {
+        self.sets
+    }
+
+    /// A label for each element's set, numbering the sets in order of their lowest element.
+    fn labels(&mut self) -> Vec<usize> {
+        let mut label = HashMap::new();
+        (0..self.parent.len())
+            .map(|x| {
+                let root = self.find(x);
+                let next = label.len();
+                *label.entry(root).or_insert(next)
+            })
+            .collect()
+    }
+}
+
+fn parse_lines<const N: usize>(lines: Vec<String>) -> anyhow::Result<Vec<Point<N>>> {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(id, l)| {
+            let coords = l
+                .split(',')
+                .map(|d| d.trim().parse::<i64>())
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(c) = coords.iter().find(|c| c.abs() >= MAX_COORDINATE) {
+                bail!("Coordinate {c} on line {} is too large", id + 1);
+            }
+            let Ok(coords) = coords.try_into() else {
+                bail!("Expected {N} coordinates on line {}: {l:?}", id + 1);
+            };
+            Ok(Point { coords, id })
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     const EXAMPLE_INPUT: &str = r"162,817,812
 57,618,57
@@ -250,50 +947,387 @@ mod tests {
 
     #[test]
     fn example() {
-        let lines = EXAMPLE_INPUT.split('\n').map(String::from).collect();
-        let result = junctions(lines, 10);
-        assert_eq!(40, result.unwrap());
+        let result = connect(&example_points(), 10, Metric::Euclidean);
+        assert_eq!(40, result.largest_product(3));
+        assert_eq!(
+            BTreeMap::from([(1, 7), (2, 2), (4, 1), (5, 1)]),
+            result.histogram
+        );
     }
 
     #[test]
     fn part2() {
-        let lines = EXAMPLE_INPUT.split('\n').map(String::from).collect();
-        let result = junctions(lines, 10000);
-        assert_eq!(25272, result.unwrap());
+        let result = connect(&example_points(), 10000, Metric::Euclidean);
+        assert_eq!(Some(25272), result.unifying_product());
+        let (a, b) = result.unifying_pair.unwrap();
+        assert_eq!((10, 12), (a.id, b.id));
+        assert_eq!(BTreeMap::from([(20, 1)]), result.histogram);
+    }
+
+    #[test]
+    fn both_parts() {
+        let points = example_points();
+        let result = connect(&points, 10, Metric::Euclidean);
+        assert_eq!(40, result.largest_product(3));
+        assert_eq!(Some(25272), result.unifying_product());
+
+        // too few boxes to ever connect, and more circuits wanted than there are
+        let result = connect(&points[..1], 10, Metric::Euclidean);
+        assert_eq!(None, result.unifying_product());
+        assert_eq!(1, result.largest_product(3));
+        let result = connect(&points[..2], 0, Metric::Euclidean);
+        assert_eq!(BTreeMap::from([(1, 2)]), result.histogram);
+        assert!(result.unifying_pair.is_some());
     }
 
     #[test]
-    fn closest_neighbours_example() {
+    fn huge_coordinates() {
+        // close together, but the product of their x coordinates is past i64::MAX
+        let lines = ["4000000000,0,0", "4000000001,0,0"].map(String::from);
+        let points = parse_lines::<3>(lines.to_vec()).unwrap();
+        let result = connect(&points, 1, Metric::Euclidean);
+        assert_eq!(Some(16_000_000_004_000_000_000), result.unifying_product());
+
+        // far enough apart that squaring the offset overflows an i64
+        let lines = ["4000000000,0,0", "0,0,0", "4000000001,0,0"].map(String::from);
+        let points = parse_lines::<3>(lines.to_vec()).unwrap();
+        let result = connect(&points, 1, Metric::Euclidean);
+        assert_eq!(vec![(1, 0, 2)], result.links);
+        assert_eq!(
+            Some((0, 1)),
+            result.unifying_pair.map(|(a, b)| (a.id, b.id))
+        );
+        let tree = Dendrogram::new(&points, Metric::Euclidean);
+        assert_eq!(16_000_000_000_000_000_000, tree.merges[1].distance);
+        for metric in METRICS {
+            let tree = KdTree::new(&points, metric);
+            assert_eq!(vec![(0, 1)], tree.nearest(&points[1], 1));
+            assert_eq!(
+                all_pairs(&points, metric),
+                candidate_edges(&points, metric).collect::<Vec<_>>()
+            );
+        }
+
+        // opposite corners of the largest space that fits are still measured exactly
+        let corner = MAX_COORDINATE - 1;
+        let lines = [
+            format!("{corner},{corner},{corner},{corner}"),
+            format!("{0},{0},{0},{0}", -corner),
+        ];
+        let points = parse_lines::<4>(lines.to_vec()).unwrap();
+        let span = 2 * corner as Distance;
+        assert_eq!(
+            4 * span * span,
+            Metric::Euclidean.distance(&points[0], &points[1])
+        );
+        assert!(parse_lines::<3>(vec![format!("{MAX_COORDINATE},0,0")]).is_err());
+        assert!(parse_lines::<3>(vec![format!("0,{},0", -MAX_COORDINATE)]).is_err());
+    }
+
+    #[test]
+    fn candidate_edges_example() {
         let points = example_points();
+        let closest = candidate_edges(&points, Metric::Euclidean)
+            .take(3)
+            .map(|(_, a, b)| (a, b))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(0, 19), (0, 7), (2, 13)], closest);
+    }
 
-        let mut circuits = (0..points.len())
-            .map(|i| HashSet::from([i]))
+    #[test]
+    fn ties_broken_by_ids() {
+        // a 3x3 grid with a duplicate of the centre box: the grid's twelve pairs 1 apart and
+        // four more from the duplicate to the centre's neighbours, and one pair 0 apart
+        let mut lines = (0..9)
+            .map(|i| format!("{},{},7", i % 3, i / 3))
             .collect::<Vec<_>>();
-        let mut point_to_circuit = (0..points.len()).collect::<Vec<usize>>();
-        let mut junctions: HashSet<(usize, usize)> = HashSet::new();
+        lines.push("1,1,7".into());
+        let points = parse_lines::<3>(lines).unwrap();
+        let edges = candidate_edges(&points, Metric::Euclidean).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (0, 4, 9),
+                (1, 0, 1),
+                (1, 0, 3),
+                (1, 1, 2),
+                (1, 1, 4),
+                (1, 1, 9),
+                (1, 2, 5),
+                (1, 3, 4),
+                (1, 3, 6),
+                (1, 3, 9),
+            ],
+            edges[..10]
+        );
+        assert_eq!(16, edges.iter().filter(|e| e.0 == 1).count());
+
+        // the lowest ids win among the ties at each step
+        let result = connect(&points, 4, Metric::Euclidean);
+        assert_eq!(vec![0, 0, 0, 0, 1, 2, 3, 4, 5, 1], result.circuit_ids);
+        let (a, b) = result.unifying_pair.unwrap();
+        assert_eq!((5, 8), (a.id, b.id));
+        for metric in METRICS {
+            assert_eq!(
+                all_pairs(&points, metric),
+                candidate_edges(&points, metric).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn query_ids() {
+        let points = example_points();
+        assert_eq!(19, junction_box(&points, 19, "--nearest").unwrap().id);
+        let error = junction_box(&points, 5000, "--nearest").unwrap_err();
+        assert_eq!(
+            "--nearest needs a junction box id below 20, not 5000",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn disjoint_set() {
+        let mut set = DisjointSet::new(5);
+        assert!(set.union(0, 1));
+        assert!(set.union(2, 3));
+        assert!(set.union(1, 3));
+        assert!(!set.union(0, 2));
+        assert_eq!(2, set.sets());
+        assert_eq!(set.find(0), set.find(3));
+        assert_eq!(vec![0, 0, 0, 0, 1], set.labels());
+        assert_eq!(BTreeMap::from([(1, 1), (4, 1)]), histogram(&set.labels()));
+    }
+
+    #[test]
+    fn input() {
+        let file = BufReader::new(File::open("src/bin/day8.txt").unwrap());
+        let lines = file.lines().map(Result::unwrap).collect::<Vec<_>>();
+        // as found by the earlier nearest-neighbour search
+        let result = connect(&parse_lines::<3>(lines).unwrap(), 1000, Metric::Euclidean);
+        assert_eq!(68112, result.largest_product(3));
+        assert_eq!(Some(44543856), result.unifying_product());
+    }
+
+    /// Random points in a small cube, so there are plenty of equal distances and repeats.
+    fn random_points<const N: usize>(rng: &mut StdRng, n: usize) -> Vec<Point<N>> {
+        (0..n)
+            .map(|id| Point {
+                coords: std::array::from_fn(|_| rng.random_range(-5..5)),
+                id,
+            })
+            .collect()
+    }
 
-        let result = closest_neighbours(&points, &circuits, &junctions);
-        assert!(matches!(result, (0, 19) | (19, 0)));
-        circuits[0].insert(19);
-        circuits[19].clear();
-        point_to_circuit[19] = 0;
-        junctions.insert((0, 19));
-        junctions.insert((19, 0));
+    fn all_pairs<const N: usize>(
+        points: &[Point<N>],
+        metric: Metric,
+    ) -> Vec<(Distance, usize, usize)> {
+        let mut pairs = vec![];
+        for (i, p) in points.iter().enumerate() {
+            for q in &points[i + 1..] {
+                pairs.push((metric.distance(p, q), p.id, q.id));
+            }
+        }
+        pairs.sort();
+        pairs
+    }
+
+    const METRICS: [Metric; 3] = [Metric::Euclidean, Metric::Manhattan, Metric::Chebyshev];
+
+    #[test]
+    fn kd_tree_queries() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for metric in METRICS {
+            check_kd_tree::<1>(&mut rng, metric);
+            check_kd_tree::<2>(&mut rng, metric);
+            check_kd_tree::<3>(&mut rng, metric);
+            check_kd_tree::<4>(&mut rng, metric);
+        }
+    }
+
+    fn check_kd_tree<const N: usize>(rng: &mut StdRng, metric: Metric) {
+        for _ in 0..100 {
+            let n = rng.random_range(0..60);
+            let points = random_points::<N>(rng, n);
+            let tree = KdTree::new(&points, metric);
+            let target = random_points(rng, 1).remove(0);
+            let mut expected = points
+                .iter()
+                .map(|p| (metric.distance(&target, p), p.id))
+                .collect::<Vec<_>>();
+            expected.sort();
 
-        let result2 = closest_neighbours(&points, &circuits, &junctions);
-        assert!(matches!(result2, (0, 7) | (7, 0)));
-        circuits[0].insert(7);
-        circuits[7].clear();
-        point_to_circuit[7] = 0;
-        junctions.insert((0, 7));
-        junctions.insert((7, 0));
+            let k = rng.random_range(0..=points.len() + 1);
+            assert_eq!(
+                expected[..k.min(points.len())],
+                tree.nearest(&target, k)[..]
+            );
+            let radius = rng.random_range(0..40);
+            let inside = expected.iter().take_while(|(d, _)| *d <= radius);
+            assert_eq!(
+                inside.copied().collect::<Vec<_>>(),
+                tree.within(&target, radius)
+            );
+        }
+    }
 
-        let results3 = closest_neighbours(&points, &circuits, &junctions);
-        assert!(matches!(results3, (2, 13) | (13, 2)));
+    #[test]
+    fn lazy_edges_in_order() {
+        let mut rng = StdRng::seed_from_u64(45);
+        for metric in METRICS {
+            for _ in 0..50 {
+                let n = rng.random_range(0..40);
+                let points = random_points::<2>(&mut rng, n);
+                let edges = candidate_edges(&points, metric).collect::<Vec<_>>();
+                assert_eq!(all_pairs(&points, metric), edges);
+                let points = random_points::<4>(&mut rng, n);
+                let edges = candidate_edges(&points, metric).collect::<Vec<_>>();
+                assert_eq!(all_pairs(&points, metric), edges);
+            }
+        }
+    }
+
+    #[test]
+    fn other_dimensions_and_metrics() {
+        // a 2D grid of sensors in two clumps, with the clumps 10 apart along a diagonal
+        let lines = ["0,0", "1,0", "0,1", "10,10", "11,10", "10,11"];
+        let points = parse_lines::<2>(lines.map(String::from).to_vec()).unwrap();
+        let result = connect(&points, 4, Metric::Manhattan);
+        assert_eq!(BTreeMap::from([(3, 2)]), result.histogram);
+        assert_eq!(vec![(1, 0, 1), (1, 0, 2)], result.links[..2]);
+        let (a, b) = connect(&points, 0, Metric::Chebyshev)
+            .unifying_pair
+            .unwrap();
+        // every box of the first clump is 10 from (10,10) by Chebyshev
+        assert_eq!((0, 3), (a.id, b.id));
+        // by Manhattan (1,0) and (0,1) are both 19 from (10,10), and the lower id wins
+        let result = connect(&points, 100, Metric::Manhattan);
+        assert_eq!(Some(10), result.unifying_product());
+        assert_eq!(Some(&(19, 1, 3)), result.links.last());
+
+        let lines = ["0,0,0,0", "0,0,0,3", "4,0,0,3", "9,9,9,9"];
+        let points = parse_lines::<4>(lines.map(String::from).to_vec()).unwrap();
+        let tree = Dendrogram::new(&points, Metric::Euclidean);
+        assert_eq!(
+            vec![9, 16, 25 + 81 * 2 + 36],
+            tree.merges.iter().map(|m| m.distance).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![0, 0, 0, 1], tree.cut_distance(16));
+        let csv = connect(&points, 1, Metric::Chebyshev).to_csv(&points);
+        assert!(csv.starts_with("id,x,y,z,w,circuit\n0,0,0,0,0,0\n1,0,0,0,3,0\n"));
+
+        assert!(parse_lines::<4>(vec!["1,2,3".into()]).is_err());
+        assert!(parse_lines::<2>(vec!["1,2,3".into()]).is_err());
+        assert!(parse_lines::<2>(vec!["1,x".into()]).is_err());
+        assert_eq!(7.0, Metric::Euclidean.length(49));
+        assert_eq!(49, Metric::Euclidean.distance_for(7));
+        assert_eq!(7, Metric::Manhattan.distance_for(7));
+    }
+
+    #[test]
+    fn exports() {
+        let points = example_points();
+        let result = connect(&points, 10, Metric::Euclidean);
+        assert_eq!(9, result.links.len());
+        assert_eq!(
+            vec![0, 1, 2, 3, 4, 5, 6, 0, 2, 7, 8, 9, 7, 2, 0, 10, 9, 2, 2, 0],
+            result.circuit_ids
+        );
+        assert_eq!(vec![4, 1, 5], result.circuit_sizes()[..3]);
+
+        let csv = result.to_csv(&points);
+        assert!(csv.starts_with("id,x,y,z,circuit\n0,162,817,812,0\n1,57,618,57,1\n"));
+        assert!(csv.contains("\n\nfrom,to,distance\n0,19,100427\n0,7,103401\n"));
+        let json = result.to_json(&points);
+        assert!(json.contains(r#"{"id": 19, "x": 425, "y": 690, "z": 689, "circuit": 0}"#));
+        assert!(json.contains(r#"{"from": 2, "to": 13, "distance": 103922}"#));
+        let dot = result.to_dot(&points);
+        assert!(dot.contains("  n0 -- n19 [label=100427];\n"));
+        assert!(dot.contains(r#"n1 [label="1\n57,618,57" style=filled fillcolor="white"];"#));
+        // the circuit of five gets the first colour, though circuit 0 has the lowest id
+        assert!(dot.contains(r##"n2 [label="2\n906,360,560" style=filled fillcolor="#1b9e77"];"##));
+        let colours = result.colours();
+        assert_eq!(
+            vec![
+                Some(PALETTE[1]),
+                Some(PALETTE[0]),
+                Some(PALETTE[2]),
+                Some(PALETTE[3])
+            ],
+            [0, 2, 7, 9].map(|c| colours[c])
+        );
+        assert_eq!(7, colours.iter().filter(|c| c.is_none()).count());
+
+        let file = env::temp_dir().join(format!("day8_test_{}.svg", std::process::id()));
+        let file = file.to_str().unwrap();
+        result.to_svg(file, &points).unwrap();
+        let svg = std::fs::read_to_string(file).unwrap();
+        assert_eq!(20 + 9, svg.matches("<use").count());
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn dendrogram() {
+        let points = example_points();
+        let tree = Dendrogram::new(&points, Metric::Euclidean);
+        assert_eq!(19, tree.merges.len());
+        assert_eq!(
+            Merge {
+                left: 0,
+                right: 19,
+                distance: 100427,
+                size: 2
+            },
+            tree.merges[0]
+        );
+        // box 7 joins the cluster made by the first merge
+        assert_eq!((20, 7, 3), {
+            let m = tree.merges[1];
+            (m.left, m.right, m.size)
+        });
+        let last = tree.merges.last().unwrap();
+        assert_eq!((20, 38), (last.size, points.len() + 18));
+        assert!(
+            tree.merges
+                .windows(2)
+                .all(|m| m[0].distance <= m[1].distance)
+        );
+
+        // cutting gives the same circuits as connecting the first ten pairs
+        let circuits = connect(&points, 10, Metric::Euclidean);
+        assert_eq!(circuits.circuit_ids, tree.cut_clusters(11));
+        assert_eq!(circuits.circuit_ids, tree.cut_distance(124564));
+        assert_eq!(circuits.circuit_ids, tree.cut_distance(124564 + 40));
+        assert_eq!(vec![0; 20], tree.cut_clusters(1));
+        assert_eq!(vec![0; 20], tree.cut_clusters(0));
+        assert_eq!((0..20).collect::<Vec<_>>(), tree.cut_clusters(20));
+        assert_eq!((0..20).collect::<Vec<_>>(), tree.cut_distance(0));
+    }
+
+    #[test]
+    fn dendrogram_ascii() {
+        let points = parse_lines::<3>(
+            ["0,0,0", "0,0,3", "0,0,10", "0,0,4"]
+                .map(String::from)
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            r"4 boxes at 6.0
+├─ box 2
+└─ 3 boxes at 3.0
+   ├─ box 0
+   └─ 2 boxes at 1.0
+      ├─ box 1
+      └─ box 3
+",
+            Dendrogram::new(&points, Metric::Euclidean).to_ascii()
+        );
+        assert_eq!("", Dendrogram::new::<3>(&[], Metric::Euclidean).to_ascii());
     }
 
     fn example_points() -> Vec<Point> {
         let lines = EXAMPLE_INPUT.split('\n').map(String::from).collect();
-        parse_lines(lines)
+        parse_lines(lines).unwrap()
     }
 }
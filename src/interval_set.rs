@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+/// Values with a well-defined next and previous value, so inclusive ranges can be split and
+/// measured.
+pub trait Discrete: Copy + Ord {
+    fn succ(self) -> Option<Self>;
+    fn pred(self) -> Option<Self>;
+    /// Number of values in `lo..=hi`.
+    fn count(lo: Self, hi: Self) -> u128;
+}
+
+macro_rules! discrete {
+    ($($t:ty),*) => {
+        $(
+            impl Discrete for $t {
+                #[inline]
+                fn succ(self) -> Option<Self> {
+                    self.checked_add(1)
+                }
+
+                #[inline]
+                fn pred(self) -> Option<Self> {
+                    self.checked_sub(1)
+                }
+
+                #[inline]
+                fn count(lo: Self, hi: Self) -> u128 {
+                    (hi as i128 - lo as i128) as u128 + 1
+                }
+            }
+        )*
+    };
+}
+
+discrete!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// A set of values stored as disjoint inclusive ranges, keyed by range start.
+///
+/// Inserting a range merges it with every range it overlaps. A coalescing set also merges ranges
+/// that merely touch, so `5..=10` and `11..=20` are stored as `5..=20`. Either way, two sets are
+/// equal when they hold the same values.
+#[derive(Clone, Debug, Default)]
+pub struct IntervalSet<T> {
+    ranges: BTreeMap<T, T>,
+    coalesce: bool,
+}
+
+impl<T: Discrete> IntervalSet<T> {
+    pub fn new() -> IntervalSet<T> {
+        IntervalSet {
+            ranges: BTreeMap::new(),
+            coalesce: false,
+        }
+    }
+
+    pub fn coalescing() -> IntervalSet<T> {
+        IntervalSet {
+            ranges: BTreeMap::new(),
+            coalesce: true,
+        }
+    }
+
+    /// An empty set with the same merging behaviour as this one.
+    fn empty_like(&self) -> IntervalSet<T> {
+        IntervalSet {
+            ranges: BTreeMap::new(),
+            coalesce: self.coalesce,
+        }
+    }
+
+    /// Whether a range ending at `end` merges with a later range starting at `start`.
+    #[inline]
+    fn joins(&self, end: T, start: T) -> bool {
+        end >= start || (self.coalesce && end.succ() == Some(start))
+    }
+
+    pub fn insert(&mut self, range: RangeInclusive<T>) {
+        let (mut start, mut end) = range.into_inner();
+        if start > end {
+            return;
+        }
+
+        if let Some((&s, &e)) = self.ranges.range(..=start).next_back()
+            && self.joins(e, start)
+        {
+            self.ranges.remove(&s);
+            start = s;
+            end = end.max(e);
+        }
+
+        let swallowed = self
+            .ranges
+            .range(start..)
+            .take_while(|&(&s, _)| self.joins(end, s))
+            .map(|(&s, &e)| (s, e))
+            .collect::<Vec<_>>();
+        for (s, e) in swallowed {
+            self.ranges.remove(&s);
+            end = end.max(e);
+        }
+
+        self.ranges.insert(start, end);
+    }
+
+    pub fn remove(&mut self, range: RangeInclusive<T>) {
+        let (start, end) = range.into_inner();
+        if start > end {
+            return;
+        }
+
+        let first = match self.ranges.range(..start).next_back() {
+            Some((&s, &e)) if e >= start => s,
+            _ => start,
+        };
+        let cut = self
+            .ranges
+            .range(first..)
+            .take_while(|&(&s, _)| s <= end)
+            .map(|(&s, &e)| (s, e))
+            .collect::<Vec<_>>();
+
+        for (s, e) in cut {
+            self.ranges.remove(&s);
+            if s < start {
+                self.ranges.insert(s, start.pred().unwrap());
+            }
+            if e > end {
+                self.ranges.insert(end.succ().unwrap(), e);
+            }
+        }
+    }
+
+    pub fn contains(&self, value: T) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, &e)| e >= value)
+    }
+
+    /// Number of values in the set that fall within `range`.
+    pub fn count_in(&self, range: RangeInclusive<T>) -> u128 {
+        let (start, end) = range.into_inner();
+        if start > end {
+            return 0;
+        }
+
+        let first = match self.ranges.range(..start).next_back() {
+            Some((&s, &e)) if e >= start => s,
+            _ => start,
+        };
+        self.ranges
+            .range(first..)
+            .take_while(|&(&s, _)| s <= end)
+            .map(|(&s, &e)| T::count(s.max(start), e.min(end)))
+            .sum()
+    }
+
+    pub fn union(&self, other: &IntervalSet<T>) -> IntervalSet<T> {
+        let mut result = self.clone();
+        result.extend(other.iter());
+        result
+    }
+
+    pub fn intersection(&self, other: &IntervalSet<T>) -> IntervalSet<T> {
+        let mut result = self.empty_like();
+        let mut left = self.iter().peekable();
+        let mut right = other.iter().peekable();
+
+        while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+            let lo = *a.start().max(b.start());
+            let hi = *a.end().min(b.end());
+            if lo <= hi {
+                result.insert(lo..=hi);
+            }
+            if a.end() < b.end() {
+                left.next();
+            } else {
+                right.next();
+            }
+        }
+        result
+    }
+
+    pub fn difference(&self, other: &IntervalSet<T>) -> IntervalSet<T> {
+        let mut result = self.clone();
+        for range in other.iter() {
+            result.remove(range);
+        }
+        result
+    }
+
+    /// Total number of values in the set.
+    pub fn len(&self) -> u128 {
+        self.ranges.iter().map(|(&s, &e)| T::count(s, e)).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Number of disjoint ranges the set is stored as.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The ranges in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = RangeInclusive<T>> + '_ {
+        self.ranges.iter().map(|(&s, &e)| s..=e)
+    }
+
+    /// The ranges as a coalescing set would store them, with touching ranges merged.
+    fn merged(&self) -> Vec<(T, T)> {
+        let mut merged: Vec<(T, T)> = Vec::with_capacity(self.ranges.len());
+        for (&s, &e) in &self.ranges {
+            match merged.last_mut() {
+                Some((_, end)) if end.succ() == Some(s) => *end = e,
+                _ => merged.push((s, e)),
+            }
+        }
+        merged
+    }
+}
+
+impl<T: Discrete> PartialEq for IntervalSet<T> {
+    fn eq(&self, other: &IntervalSet<T>) -> bool {
+        self.merged() == other.merged()
+    }
+}
+
+impl<T: Discrete> Eq for IntervalSet<T> {}
+
+impl<T: Discrete> Extend<RangeInclusive<T>> for IntervalSet<T> {
+    fn extend<I: IntoIterator<Item = RangeInclusive<T>>>(&mut self, iter: I) {
+        for range in iter {
+            self.insert(range);
+        }
+    }
+}
+
+impl<T: Discrete> FromIterator<RangeInclusive<T>> for IntervalSet<T> {
+    fn from_iter<I: IntoIterator<Item = RangeInclusive<T>>>(iter: I) -> IntervalSet<T> {
+        let mut set = IntervalSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeSet;
+
+    fn ranges(set: &IntervalSet<u8>) -> Vec<RangeInclusive<u8>> {
+        set.iter().collect()
+    }
+
+    fn points(set: &IntervalSet<u8>) -> BTreeSet<u8> {
+        set.iter().flatten().collect()
+    }
+
+    fn random_range(rng: &mut StdRng) -> RangeInclusive<u8> {
+        let start = rng.random::<u8>();
+        let end = start.saturating_add(rng.random_range(0..40));
+        start..=end
+    }
+
+    /// Applies random inserts and removes to both an `IntervalSet` and a `BTreeSet` of points.
+    fn random_set(rng: &mut StdRng) -> (IntervalSet<u8>, BTreeSet<u8>) {
+        let mut set = if rng.random_bool(0.5) {
+            IntervalSet::coalescing()
+        } else {
+            IntervalSet::new()
+        };
+        let mut expected = BTreeSet::new();
+        for _ in 0..rng.random_range(0..30) {
+            let range = random_range(rng);
+            if rng.random_bool(0.7) {
+                expected.extend(range.clone());
+                set.insert(range);
+            } else {
+                for x in range.clone() {
+                    expected.remove(&x);
+                }
+                set.remove(range);
+            }
+        }
+        (set, expected)
+    }
+
+    fn check(set: &IntervalSet<u8>, expected: &BTreeSet<u8>) {
+        assert_eq!(expected, &points(set));
+        assert_eq!(expected.len() as u128, set.len());
+        assert_eq!(expected.is_empty(), set.is_empty());
+        for x in 0..=u8::MAX {
+            assert_eq!(expected.contains(&x), set.contains(x), "contains({x})");
+        }
+
+        // stored ranges are sorted and disjoint
+        let ranges = set.iter().collect::<Vec<_>>();
+        for pair in ranges.windows(2) {
+            assert!(pair[0].end() < pair[1].start(), "{ranges:?}");
+            if set.coalesce {
+                assert!(*pair[0].end() + 1 < *pair[1].start(), "{ranges:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn insert_merges_overlaps() {
+        let set = IntervalSet::from_iter([3..=5, 10..=14, 16..=20, 12..=18]);
+        assert_eq!(vec![3..=5, 10..=20], set.iter().collect::<Vec<_>>());
+        assert_eq!(14, set.len());
+        assert_eq!(2, set.range_count());
+    }
+
+    #[test]
+    fn coalesce_touching() {
+        let mut set = IntervalSet::from_iter([5..=10, 11..=20, 22..=30]);
+        assert_eq!(vec![5..=10, 11..=20, 22..=30], ranges(&set));
+        assert!(set.contains(11));
+        assert_eq!(25, set.len());
+
+        let mut coalesced = IntervalSet::coalescing();
+        coalesced.extend([11..=20, 5..=10, 22..=30]);
+        assert_eq!(vec![5..=20, 22..=30], ranges(&coalesced));
+        coalesced.insert(21..=21);
+        assert_eq!(vec![5..=30], ranges(&coalesced));
+
+        set.insert(21..=21);
+        assert_eq!(4, set.range_count());
+        assert_eq!(set.len(), coalesced.len());
+    }
+
+    #[test]
+    fn equal_by_members() {
+        let split = IntervalSet::from_iter([1..=5, 6..=10]);
+        let whole = IntervalSet::from_iter([1..=10]);
+        assert_eq!(2, split.range_count());
+        assert_eq!(split, whole);
+
+        let mut coalesced = IntervalSet::coalescing();
+        coalesced.extend([6..=10, 1..=5]);
+        assert_eq!(split, coalesced);
+        assert_eq!(coalesced, whole);
+        assert_eq!(IntervalSet::<u8>::new(), IntervalSet::coalescing());
+
+        assert_ne!(whole, IntervalSet::from_iter([1..=4, 6..=10]));
+        assert_ne!(whole, IntervalSet::from_iter([1..=11]));
+        assert_ne!(whole, IntervalSet::new());
+    }
+
+    #[test]
+    fn remove_splits() {
+        let mut set = IntervalSet::from_iter([0..=10]);
+        set.remove(3..=4);
+        assert_eq!(vec![0..=2, 5..=10], set.iter().collect::<Vec<_>>());
+        set.remove(0..=0);
+        set.remove(10..=20);
+        assert_eq!(vec![1..=2, 5..=9], set.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn extremes() {
+        let mut set = IntervalSet::from_iter([0..=u64::MAX]);
+        assert_eq!(u64::MAX as u128 + 1, set.len());
+        set.remove(0..=0);
+        set.remove(u64::MAX..=u64::MAX);
+        assert_eq!(vec![1..=u64::MAX - 1], set.iter().collect::<Vec<_>>());
+        assert!(!set.contains(u64::MAX));
+
+        let signed = IntervalSet::from_iter([i8::MIN..=i8::MAX]);
+        assert_eq!(256, signed.len());
+    }
+
+    #[test]
+    fn random_inserts_and_removes() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..500 {
+            let (set, expected) = random_set(&mut rng);
+            check(&set, &expected);
+
+            let range = random_range(&mut rng);
+            assert_eq!(
+                expected.range(range.clone()).count() as u128,
+                set.count_in(range.clone()),
+                "count_in({range:?}) of {:?}",
+                ranges(&set)
+            );
+        }
+    }
+
+    #[test]
+    fn random_set_operations() {
+        let mut rng = StdRng::seed_from_u64(29);
+        for _ in 0..500 {
+            let (a, a_points) = random_set(&mut rng);
+            let (b, b_points) = random_set(&mut rng);
+
+            check(&a.union(&b), &a_points.union(&b_points).copied().collect());
+            check(
+                &a.intersection(&b),
+                &a_points.intersection(&b_points).copied().collect(),
+            );
+            check(
+                &a.difference(&b),
+                &a_points.difference(&b_points).copied().collect(),
+            );
+
+            assert_eq!(a_points == b_points, a == b);
+            assert_eq!(a.union(&b), b.union(&a));
+            // one range per point, however the set was built
+            let rebuilt = IntervalSet::from_iter(a_points.iter().map(|&x| x..=x));
+            assert_eq!(a, rebuilt);
+            assert_eq!(a.difference(&b) == a, a.intersection(&b).is_empty());
+        }
+    }
+}
@@ -0,0 +1,3 @@
+//! Helpers shared between the daily puzzles in `src/bin`.
+
+pub mod interval_set;